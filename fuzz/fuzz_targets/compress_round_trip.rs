@@ -0,0 +1,31 @@
+#![no_main]
+
+use bytes::BytesMut;
+use compression_service::compress::Compressor;
+use compression_service::message::StatusCode;
+use libfuzzer_sys::fuzz_target;
+
+// The bytes are compressed as they are, which mostly exercises the input checks, then
+// again mapped to three letters, which makes plenty of runs. Whatever compresses must
+// decompress back to the same bytes.
+fuzz_target!(|data: &[u8]| {
+    let mut compressor = Compressor::new();
+    if let Ok(compressed) = compressor.compress(BytesMut::from(data)) {
+        assert_eq!(
+            Compressor::decompress(&compressed, data.len()),
+            Ok(BytesMut::from(data))
+        );
+    }
+
+    let letters: Vec<u8> = data.iter().map(|byte| b'a' + byte % 3).collect();
+    match compressor.compress(BytesMut::from(&letters[..])) {
+        Ok(compressed) => {
+            assert!(compressed.len() <= letters.len(), "compress made it longer");
+            assert_eq!(
+                Compressor::decompress(&compressed, letters.len()),
+                Ok(BytesMut::from(&letters[..]))
+            );
+        }
+        Err(status) => assert!(letters.is_empty() && status == StatusCode::EmptyBuffer),
+    }
+});
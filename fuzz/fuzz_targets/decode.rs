@@ -0,0 +1,62 @@
+#![no_main]
+
+use bytes::BytesMut;
+use compression_service::message::{RequestCode, StatusCode};
+use compression_service::packet::PacketCodec;
+use libfuzzer_sys::fuzz_target;
+use tokio_util::codec::Decoder;
+
+/// Decodes packets until the codec needs more bytes, like the server's connection loop.
+fn decode_all(
+    codec: &mut PacketCodec,
+    buffer: &mut BytesMut,
+    results: &mut Vec<Result<RequestCode, StatusCode>>,
+) {
+    loop {
+        match codec.decode(buffer) {
+            Ok(Some(request)) => results.push(Ok(request)),
+            Ok(None) => return,
+            Err(status) => results.push(Err(status)), // the server keeps reading
+        }
+    }
+}
+
+// The first byte is how many chunk lengths follow it, from 1 up to 8, and the rest are
+// the bytes received by the server. The bytes arrive in chunks of those lengths, cycling
+// through them, where 0 means all of the remaining bytes. However the bytes are split,
+// they must decode to the same requests and errors as when they arrive all at once.
+fuzz_target!(|data: &[u8]| {
+    let (count, rest) = match data.split_first() {
+        Some((count, rest)) => ((*count as usize % 8) + 1, rest),
+        None => return,
+    };
+    if rest.len() < count {
+        return;
+    }
+    let (lengths, data) = rest.split_at(count);
+
+    let mut whole = PacketCodec::new_with_max_payload(4096).unwrap();
+    let mut expected = Vec::new();
+    decode_all(&mut whole, &mut BytesMut::from(data), &mut expected);
+
+    let mut split = PacketCodec::new_with_max_payload(4096).unwrap();
+    let mut results = Vec::new();
+    let mut buffer = BytesMut::new();
+    let mut remaining = data;
+    for length in lengths.iter().cycle() {
+        if remaining.is_empty() {
+            break;
+        }
+        let length = match *length as usize {
+            0 => remaining.len(),
+            length => length.min(remaining.len()),
+        };
+        let (chunk, rest) = remaining.split_at(length);
+        buffer.extend_from_slice(chunk);
+        decode_all(&mut split, &mut buffer, &mut results);
+        remaining = rest;
+    }
+
+    assert_eq!(results, expected);
+    assert_eq!(split.get_stats(), whole.get_stats());
+});
@@ -0,0 +1,27 @@
+#![no_main]
+
+use compression_service::compress::Compressor;
+use libfuzzer_sys::fuzz_target;
+
+/// Most bytes an input may decompress to, enough for runs longer than a max payload.
+const MAX_LEN: usize = 1 << 16;
+
+// The bytes are decompressed as they are, which must fail rather than panic or run out of
+// memory on a huge number. Whatever decompresses must compress to something that
+// decompresses back to the same bytes, though not necessarily to the input, ie. "aa"
+// compresses to itself but "2a" decompresses to it too.
+fuzz_target!(|data: &[u8]| {
+    // "0a" decompresses to nothing, which doesn't compress
+    let decompressed = match Compressor::decompress(data, MAX_LEN) {
+        Ok(decompressed) if !decompressed.is_empty() => decompressed,
+        _ => return,
+    };
+    assert!(decompressed.len() <= MAX_LEN, "decompress ignored max_len");
+
+    let mut compressor = Compressor::new();
+    let compressed = compressor.compress(decompressed.clone()).unwrap();
+    assert_eq!(
+        Compressor::decompress(&compressed, decompressed.len()),
+        Ok(decompressed)
+    );
+});
@@ -0,0 +1,282 @@
+use compression_service::client::{Client, Error};
+use compression_service::message::StatusCode;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::BTreeMap;
+use std::process;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use structopt::StructOpt;
+
+/// Exit code when the server can't be reached, like compression-client.
+const EXIT_UNAVAILABLE: i32 = 69;
+
+#[derive(StructOpt)]
+#[structopt(about = "Measures the throughput and latency of a compression service")]
+struct Args {
+    /// Address and port, or unix:path of the server
+    #[structopt(short, long, default_value = "localhost:4000")]
+    addr: String,
+
+    /// Number of concurrent connections, each sending one request at a time
+    #[structopt(short, long, default_value = "10")]
+    connections: usize,
+
+    /// Seconds to send requests for
+    #[structopt(short, long, default_value = "10")]
+    duration: u64,
+
+    /// Seconds to wait for the server to connect and respond
+    #[structopt(short, long, default_value = "5")]
+    timeout: u64,
+
+    /// Shared secret key to authenticate with
+    #[structopt(long, env = "COMPRESSION_SERVICE_KEY", hide_env_values = true)]
+    key: Option<String>,
+
+    /// Relative number of Ping requests in the mix
+    #[structopt(long, default_value = "1")]
+    ping: u32,
+
+    /// Relative number of Compress requests in the mix
+    #[structopt(long, default_value = "8")]
+    compress: u32,
+
+    /// Relative number of GetStats requests in the mix
+    #[structopt(long, default_value = "1")]
+    stats: u32,
+
+    /// Compress payloads of runs of repeated letters, or random letters
+    #[structopt(long, default_value = "runs", possible_values = &["runs", "random"])]
+    payload: Payload,
+
+    /// Compress payload length in bytes
+    #[structopt(long, default_value = "1024")]
+    payload_len: usize,
+}
+
+/// How Compress payloads are generated.
+#[derive(Clone, Copy)]
+enum Payload {
+    Runs,   // runs of 1 to 20 repeated letters, which compress well
+    Random, // random letters, which barely compress
+}
+
+impl FromStr for Payload {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "runs" => Ok(Payload::Runs),
+            "random" => Ok(Payload::Random),
+            _ => Err(format!("unknown payload '{}'", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Request {
+    Ping,
+    Compress,
+    GetStats,
+}
+
+impl Request {
+    fn name(&self) -> &'static str {
+        match self {
+            Request::Ping => "ping",
+            Request::Compress => "compress",
+            Request::GetStats => "get_stats",
+        }
+    }
+}
+
+/// What one connection measured.
+#[derive(Default)]
+struct Results {
+    latencies: Vec<u32>, // microseconds of each request, including failed ones
+    requests: BTreeMap<&'static str, usize>, // by request name
+    errors: BTreeMap<&'static str, usize>, // by status code name or error kind
+}
+
+impl Results {
+    fn merge(&mut self, other: Results) {
+        self.latencies.extend(other.latencies);
+        for (name, count) in other.requests {
+            *self.requests.entry(name).or_insert(0) += count;
+        }
+        for (name, count) in other.errors {
+            *self.errors.entry(name).or_insert(0) += count;
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::from_args();
+    let mix = [
+        (Request::Ping, args.ping),
+        (Request::Compress, args.compress),
+        (Request::GetStats, args.stats),
+    ];
+    if args.connections == 0 || mix.iter().all(|(_, weight)| *weight == 0) {
+        eprintln!("error: nothing to send, set --connections and a request weight above 0");
+        process::exit(2);
+    }
+
+    // connect every client before starting the clock
+    let timeout = Duration::from_secs(args.timeout);
+    let mut clients = Vec::with_capacity(args.connections);
+    for _ in 0..args.connections {
+        match connect(&args.addr, timeout, args.key.as_deref()).await {
+            Ok(client) => clients.push(client),
+            Err(error) => {
+                eprintln!("error: {}: {}", args.addr, error);
+                process::exit(EXIT_UNAVAILABLE);
+            }
+        }
+    }
+
+    let start = Instant::now();
+    let deadline = start + Duration::from_secs(args.duration);
+    let tasks: Vec<_> = clients
+        .into_iter()
+        .map(|client| {
+            let payload = (args.payload, args.payload_len);
+            tokio::spawn(run(client, deadline, mix, payload))
+        })
+        .collect();
+
+    let mut results = Results::default();
+    for task in tasks {
+        results.merge(task.await.expect("connection task panicked"));
+    }
+    print!("{}", report(&mut results, start.elapsed()));
+}
+
+async fn connect(addr: &str, timeout: Duration, key: Option<&str>) -> Result<Client, Error> {
+    let mut client = Client::connect(addr, timeout).await?;
+    if let Some(key) = key {
+        client.authenticate(key).await?;
+    }
+    Ok(client)
+}
+
+/// Sends requests one at a time until the deadline.
+async fn run(
+    mut client: Client,
+    deadline: Instant,
+    mix: [(Request, u32); 3],
+    (payload, payload_len): (Payload, usize),
+) -> Results {
+    let mut rng = StdRng::from_entropy();
+    let total: u32 = mix.iter().map(|(_, weight)| weight).sum();
+    let mut results = Results::default();
+
+    while Instant::now() < deadline {
+        // pick a request in proportion to its weight
+        let mut pick = rng.gen_range(0..total);
+        let (request, _) = mix
+            .iter()
+            .find(|(_, weight)| {
+                let found = pick < *weight;
+                pick = pick.saturating_sub(*weight);
+                found
+            })
+            .expect("pick is less than the total weight");
+
+        // generate the payload before starting the clock
+        let buffer = match request {
+            Request::Compress => generate(&mut rng, payload, payload_len),
+            _ => Vec::new(),
+        };
+
+        let start = Instant::now();
+        let result = match request {
+            Request::Ping => client.ping().await,
+            Request::Compress => client.compress(&buffer).await.map(|_| ()),
+            Request::GetStats => client.get_stats().await.map(|_| ()),
+        };
+        let latency = start.elapsed().as_micros().min(u32::MAX as u128) as u32;
+
+        results.latencies.push(latency);
+        *results.requests.entry(request.name()).or_insert(0) += 1;
+        if let Err(error) = result {
+            *results.errors.entry(error_name(&error)).or_insert(0) += 1;
+        }
+    }
+
+    results
+}
+
+/// Returns a payload of lowercase ascii letters.
+fn generate(rng: &mut StdRng, payload: Payload, len: usize) -> Vec<u8> {
+    let mut buffer = Vec::with_capacity(len);
+    while buffer.len() < len {
+        let letter = rng.gen_range(b'a'..=b'z');
+        let run = match payload {
+            Payload::Runs => rng.gen_range(1..=20),
+            Payload::Random => 1,
+        };
+        let run = run.min(len - buffer.len());
+        buffer.resize(buffer.len() + run, letter);
+    }
+    buffer
+}
+
+fn error_name(error: &Error) -> &'static str {
+    match error {
+        Error::Status(status) => StatusCode::name(status.value()),
+        Error::Io(_) => "io_error",
+        Error::Timeout => "client_timeout",
+        Error::Closed => "closed",
+        Error::InvalidResponse(_) => "invalid_response",
+    }
+}
+
+/// Formats the requests per second, latency percentiles, and error counts.
+fn report(results: &mut Results, elapsed: Duration) -> String {
+    let total = results.latencies.len();
+    let seconds = elapsed.as_secs_f64();
+    let mut report = format!(
+        "requests: {} in {:.2} s, {:.1} per second\n",
+        total,
+        seconds,
+        total as f64 / seconds
+    );
+
+    let requests: Vec<_> = results
+        .requests
+        .iter()
+        .map(|(name, count)| format!("{} {}", name, count))
+        .collect();
+    report.push_str(&format!("mix: {}\n", requests.join(", ")));
+
+    results.latencies.sort_unstable();
+    let percentile = |percent: usize| {
+        let index = (total * percent / 100).min(total.saturating_sub(1));
+        results.latencies.get(index).copied().unwrap_or(0) as f64 / 1000.0
+    };
+    report.push_str(&format!(
+        "latency: p50 {:.3} ms, p90 {:.3} ms, p99 {:.3} ms, max {:.3} ms\n",
+        percentile(50),
+        percentile(90),
+        percentile(99),
+        percentile(100)
+    ));
+
+    let errors: usize = results.errors.values().sum();
+    let kinds: Vec<_> = results
+        .errors
+        .iter()
+        .map(|(name, count)| format!("{} {}", name, count))
+        .collect();
+    if kinds.is_empty() {
+        report.push_str("errors: 0\n");
+    } else {
+        report.push_str(&format!("errors: {} ({})\n", errors, kinds.join(", ")));
+    }
+
+    report
+}
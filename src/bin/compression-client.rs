@@ -0,0 +1,327 @@
+use compression_service::client::{Client, Error};
+use compression_service::compress::Compressor;
+use compression_service::message::StatusCode;
+
+use bytes::BytesMut;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::PathBuf;
+use std::process;
+use std::time::{Duration, Instant};
+use structopt::StructOpt;
+
+/// Bytes compressed or decompressed at a time by the offline modes, the server's default
+/// max payload length.
+const CHUNK_LEN: usize = 1 << 14;
+/// Most bytes a chunk may decompress to. A chunk compressed by the server or these modes
+/// has runs no longer than a max payload, at most 32767 letters, written in six bytes.
+const MAX_DECOMPRESSED_LEN: usize = (CHUNK_LEN / 6 + 1) * 32767;
+
+/// Exit code when the server can't be reached or doesn't respond in time.
+const EXIT_UNAVAILABLE: i32 = 69;
+/// Exit code when the input file can't be read.
+const EXIT_NO_INPUT: i32 = 66;
+/// Exit code when writing the output fails.
+const EXIT_IO_ERROR: i32 = 74;
+/// Exit code when the server sends a response that doesn't follow the protocol.
+const EXIT_PROTOCOL: i32 = 76;
+
+#[derive(StructOpt)]
+#[structopt(
+    about = "Sends requests to a compression service",
+    after_help = "A request that gets an error status code exits with the status code value."
+)]
+struct Args {
+    /// Address and port, or unix:path of the server
+    #[structopt(short, long, default_value = "localhost:4000")]
+    addr: String,
+
+    /// Seconds to wait for the server to connect and respond
+    #[structopt(short, long, default_value = "5")]
+    timeout: u64,
+
+    /// Shared secret key to authenticate with
+    #[structopt(long, env = "COMPRESSION_SERVICE_KEY", hide_env_values = true)]
+    key: Option<String>,
+
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(StructOpt)]
+enum Command {
+    /// Checks that the server responds, and prints the round trip time
+    Ping,
+
+    /// Prints the server's stats
+    Stats {
+        /// Print a JSON object instead
+        #[structopt(long)]
+        json: bool,
+    },
+
+    /// Resets the server's stats, needs an admin key when authentication is on
+    Reset,
+
+    /// Compresses a file, or stdin, and writes the result to stdout. A trailing newline
+    /// is not compressed, and is written after the result
+    Compress {
+        /// Compress locally in chunks instead of sending a request, for inputs of any
+        /// length, and print the compression ratio to stderr
+        #[structopt(long)]
+        offline: bool,
+
+        /// File to compress, stdin when omitted
+        #[structopt(parse(from_os_str))]
+        file: Option<PathBuf>,
+    },
+
+    /// Decompresses a file, or stdin, locally in chunks and writes the result to stdout.
+    /// A trailing newline is passed through like compress, and the compression ratio is
+    /// printed to stderr
+    Decompress {
+        /// File to decompress, stdin when omitted
+        #[structopt(parse(from_os_str))]
+        file: Option<PathBuf>,
+    },
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::from_args();
+    if let Err((code, message)) = run(args).await {
+        eprintln!("error: {}", message);
+        process::exit(code);
+    }
+}
+
+async fn run(args: Args) -> Result<(), (i32, String)> {
+    let Args {
+        addr,
+        timeout,
+        key,
+        command,
+    } = args;
+    let failed = |error| failed(&addr, error);
+
+    // offline modes don't need a server
+    match &command {
+        Command::Compress {
+            offline: true,
+            file,
+        } => {
+            let mut compressor = Compressor::new();
+            let (before, after) =
+                offline(file.as_ref(), run_start, |chunk| compressor.compress(chunk))?;
+            eprintln!("compression ratio: {}%", ratio(before, after));
+            return Ok(());
+        }
+        Command::Decompress { file } => {
+            let (after, before) = offline(file.as_ref(), count_start, |chunk| {
+                Compressor::decompress(&chunk, MAX_DECOMPRESSED_LEN)
+            })?;
+            eprintln!("compression ratio: {}%", ratio(before, after));
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    // read input before connecting, so a missing file doesn't wait on the server
+    let input = match &command {
+        Command::Compress { file, .. } => Some(read_input(file.as_ref())?),
+        _ => None,
+    };
+
+    let timeout = Duration::from_secs(timeout);
+    let mut client = Client::connect(&addr, timeout).await.map_err(failed)?;
+    if let Some(key) = &key {
+        client.authenticate(key).await.map_err(failed)?;
+    }
+
+    let output = match command {
+        Command::Ping => {
+            let start = Instant::now();
+            client.ping().await.map_err(failed)?;
+            format!("ok in {:.3} ms\n", start.elapsed().as_secs_f64() * 1000.0)
+        }
+        Command::Stats { json } => {
+            let stats = client.get_stats().await.map_err(failed)?;
+            if json {
+                format!(
+                    "{}\n",
+                    serde_json::to_string(&stats).expect("stats are valid json")
+                )
+            } else {
+                format!(
+                    "received: {} bytes\nsent: {} bytes\ncompression ratio: {}%\n",
+                    stats.received, stats.sent, stats.ratio
+                )
+            }
+        }
+        Command::Reset => {
+            client.reset_stats().await.map_err(failed)?;
+            String::new()
+        }
+        Command::Decompress { .. } => unreachable!("decompress is offline"),
+        Command::Compress { .. } => {
+            let mut input = BytesMut::from(&input.expect("input was read")[..]);
+            let newline = strip_newline(&mut input);
+            let compressed = client.compress(&input).await.map_err(failed)?;
+            let mut output = String::from_utf8_lossy(&compressed).into_owned();
+            output.push_str(newline);
+            output
+        }
+    };
+
+    io::stdout()
+        .write_all(output.as_bytes())
+        .map_err(|error| (EXIT_IO_ERROR, format!("stdout: {}", error)))
+}
+
+fn read_input(file: Option<&PathBuf>) -> Result<Vec<u8>, (i32, String)> {
+    let mut input = Vec::new();
+    let result = match file {
+        Some(path) => fs::read(path).map(|contents| input = contents),
+        None => io::stdin().read_to_end(&mut input).map(|_| ()),
+    };
+
+    let name = file.map_or_else(|| String::from("stdin"), |path| path.display().to_string());
+    result
+        .map(|_| input)
+        .map_err(|error| (EXIT_NO_INPUT, format!("{}: {}", name, error)))
+}
+
+/// Compresses or decompresses a file, or stdin, to stdout a chunk at a time. Each chunk
+/// ends where split says, so that chunks don't end in the middle of a run of letters or
+/// a number, and the leftover bytes start the next chunk. Returns the number of bytes
+/// read and written, not counting a trailing newline.
+fn offline<F>(
+    file: Option<&PathBuf>,
+    split: fn(&[u8]) -> usize,
+    mut process: F,
+) -> Result<(usize, usize), (i32, String)>
+where
+    F: FnMut(BytesMut) -> Result<BytesMut, StatusCode>,
+{
+    let name = file.map_or_else(|| String::from("stdin"), |path| path.display().to_string());
+    let read_failed = |error: io::Error| (EXIT_NO_INPUT, format!("{}: {}", name, error));
+    let write_failed = |error: io::Error| (EXIT_IO_ERROR, format!("stdout: {}", error));
+
+    let mut input: Box<dyn Read> = match file {
+        Some(path) => Box::new(File::open(path).map_err(read_failed)?),
+        None => Box::new(io::stdin()),
+    };
+    let stdout = io::stdout();
+    let mut output = BufWriter::new(stdout.lock());
+    let (mut before, mut after) = (0, 0);
+    let mut buffer = BytesMut::with_capacity(CHUNK_LEN);
+
+    loop {
+        let start = buffer.len();
+        buffer.resize(CHUNK_LEN, 0);
+        let len = fill(&mut *input, &mut buffer[start..]).map_err(read_failed)?;
+        buffer.truncate(start + len);
+
+        // a partial chunk is the last one
+        let last = buffer.len() < CHUNK_LEN;
+        let newline = if last { strip_newline(&mut buffer) } else { "" };
+        let end = if last { buffer.len() } else { split(&buffer) };
+
+        let chunk = buffer.split_to(end);
+        if !chunk.is_empty() {
+            let chunk_len = chunk.len();
+            let result = process(chunk).map_err(status_failed)?;
+            output.write_all(&result).map_err(write_failed)?;
+            before += chunk_len;
+            after += result.len();
+        }
+
+        if last {
+            output.write_all(newline.as_bytes()).map_err(write_failed)?;
+            output.flush().map_err(write_failed)?;
+            break;
+        }
+    }
+
+    Ok((before, after))
+}
+
+/// Reads into buffer until it's full or the input ends. Returns the number of bytes read.
+fn fill(input: &mut dyn Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match input.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(len) => filled += len,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(filled)
+}
+
+/// Returns where the run of letters at the end of a chunk to compress starts, or the end
+/// of the chunk if it's one long run.
+fn run_start(chunk: &[u8]) -> usize {
+    let last = chunk[chunk.len() - 1];
+    chunk
+        .iter()
+        .rposition(|&byte| byte != last)
+        .map_or(chunk.len(), |index| index + 1)
+}
+
+/// Returns where the number at the end of a chunk to decompress starts, or the end of
+/// the chunk if it doesn't end with a number.
+fn count_start(chunk: &[u8]) -> usize {
+    chunk
+        .iter()
+        .rposition(|byte| !byte.is_ascii_digit())
+        .map_or(chunk.len(), |index| index + 1)
+}
+
+/// Payload bytes after compression as a percentage of payload bytes before compression.
+fn ratio(before: usize, after: usize) -> u8 {
+    if before == 0 {
+        0
+    } else {
+        ((after as f32) / (before as f32) * 100.0) as u8
+    }
+}
+
+/// Removes a trailing newline, ie. from echo, and returns it.
+fn strip_newline(input: &mut BytesMut) -> &'static str {
+    if input.ends_with(b"\r\n") {
+        input.truncate(input.len() - 2);
+        "\r\n"
+    } else if input.ends_with(b"\n") {
+        input.truncate(input.len() - 1);
+        "\n"
+    } else {
+        ""
+    }
+}
+
+/// Returns the exit code and message for a failed request.
+fn failed(addr: &str, error: Error) -> (i32, String) {
+    let code = match &error {
+        Error::Status(status) => status.value() as i32,
+        Error::Io(_) | Error::Timeout | Error::Closed => EXIT_UNAVAILABLE,
+        Error::InvalidResponse(_) => EXIT_PROTOCOL,
+    };
+    let message = match &error {
+        Error::Status(status) => format!(
+            "{} ({} status code {})",
+            error,
+            StatusCode::name(status.value()),
+            status.value()
+        ),
+        _ => format!("{}: {}", addr, error),
+    };
+    (code, message)
+}
+
+/// Returns the exit code and message for a chunk that failed to compress or decompress
+/// offline, the same as if the server had responded with status.
+fn status_failed(status: StatusCode) -> (i32, String) {
+    failed("", Error::Status(status))
+}
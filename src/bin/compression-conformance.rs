@@ -0,0 +1,675 @@
+use compression_service::client::sign;
+use compression_service::message::StatusCode;
+
+use std::collections::BTreeSet;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+use std::process;
+use std::thread;
+use std::time::Duration;
+use structopt::StructOpt;
+
+/// Exit code when the server can't be reached, like compression-client.
+const EXIT_UNAVAILABLE: i32 = 69;
+
+/// Every defined status code value.
+const STATUS_CODES: [u16; 14] = [0, 1, 2, 3, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42];
+
+#[derive(StructOpt)]
+#[structopt(
+    about = "Checks that a server follows the STRY packet protocol",
+    after_help = "Exits with 0 when every check passes, and 1 when any check fails."
+)]
+struct Args {
+    /// Address and port, or unix:path of the server
+    #[structopt(short, long, default_value = "localhost:4000")]
+    addr: String,
+
+    /// Seconds to wait for each response
+    #[structopt(short, long, default_value = "5")]
+    timeout: u64,
+
+    /// Shared secret admin key to authenticate each connection with
+    #[structopt(long, env = "COMPRESSION_SERVICE_KEY", hide_env_values = true)]
+    key: Option<String>,
+
+    /// Skip the checks that reset the server's stats, ie. for a deployed server
+    #[structopt(long)]
+    no_reset: bool,
+
+    /// Shared secret user key, to check that a user can't reset the stats
+    #[structopt(long, env = "COMPRESSION_SERVICE_USER_KEY", hide_env_values = true)]
+    user_key: Option<String>,
+
+    /// The server's packet timeout in seconds, to check that it times out a stalled packet
+    #[structopt(long)]
+    packet_timeout: Option<u64>,
+
+    /// The server's connection limit, to check that it rejects one more connection. The
+    /// server must reject busy connections rather than queue them
+    #[structopt(long)]
+    max_connections: Option<usize>,
+
+    /// The server's requests per second limit, to check that it limits a client over it
+    #[structopt(long)]
+    rate_limit: Option<u32>,
+}
+
+/// A stream to the server that reads and writes with a timeout.
+trait Stream: Read + Write {}
+
+impl<T: Read + Write> Stream for T {}
+
+/// A connection to the server that remembers every status code it received.
+struct Connection {
+    stream: Box<dyn Stream>,
+    statuses: BTreeSet<u16>,
+}
+
+/// A response packet.
+struct Response {
+    status: u16,
+    payload: Vec<u8>,
+}
+
+impl Connection {
+    fn open(addr: &str, timeout: Duration) -> io::Result<Connection> {
+        let stream: Box<dyn Stream> = match addr.strip_prefix("unix:") {
+            Some(path) => {
+                let stream = UnixStream::connect(path)?;
+                stream.set_read_timeout(Some(timeout))?;
+                stream.set_write_timeout(Some(timeout))?;
+                Box::new(stream)
+            }
+            None => {
+                let stream = TcpStream::connect(addr)?;
+                stream.set_read_timeout(Some(timeout))?;
+                stream.set_write_timeout(Some(timeout))?;
+                stream.set_nodelay(true)?; // so split packets arrive split
+                Box::new(stream)
+            }
+        };
+
+        Ok(Connection {
+            stream,
+            statuses: BTreeSet::new(),
+        })
+    }
+
+    fn send(&mut self, bytes: &[u8]) -> Result<(), String> {
+        self.stream
+            .write_all(bytes)
+            .map_err(|error| format!("send failed: {}", error))
+    }
+
+    fn receive(&mut self) -> Result<Response, String> {
+        let mut header = [0; 8];
+        self.stream
+            .read_exact(&mut header)
+            .map_err(|error| format!("no response: {}", error))?;
+        if &header[..4] != b"STRY" {
+            return Err(format!("response has no magic header: {:?}", header));
+        }
+
+        let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+        let status = u16::from_be_bytes([header[6], header[7]]);
+        let mut payload = vec![0; length];
+        self.stream
+            .read_exact(&mut payload)
+            .map_err(|error| format!("response payload of {} bytes missing: {}", length, error))?;
+
+        self.statuses.insert(status);
+        Ok(Response { status, payload })
+    }
+
+    /// Sends bytes and checks that the response has the status code value. Returns the
+    /// response payload.
+    fn expect(&mut self, bytes: &[u8], status: u16) -> Result<Vec<u8>, String> {
+        self.send(bytes)?;
+        self.expect_response(status)
+    }
+
+    fn expect_response(&mut self, status: u16) -> Result<Vec<u8>, String> {
+        let response = self.receive()?;
+        if response.status != status {
+            return Err(format!(
+                "expected status {} {}, got {} {}",
+                status,
+                StatusCode::name(status),
+                response.status,
+                StatusCode::name(response.status)
+            ));
+        }
+        Ok(response.payload)
+    }
+
+    /// Checks that the connection is still usable, ie. after garbage or an invalid packet.
+    fn expect_ping(&mut self) -> Result<(), String> {
+        let payload = self
+            .expect(&packet(1, b""), 0)
+            .map_err(|error| format!("ping afterwards failed: {}", error))?;
+        expect_payload(&payload, b"")
+    }
+
+    fn authenticate(&mut self, key: &str) -> Result<(), String> {
+        let challenge = self.expect(&packet(6, b""), 0)?;
+        self.expect(&packet(6, &sign(key, &challenge)), 0)?;
+        Ok(())
+    }
+}
+
+/// Returns a packet with a payload length field that matches the payload.
+fn packet(request: u16, payload: &[u8]) -> Vec<u8> {
+    let mut packet = header(payload.len() as u16, request);
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// Returns a packet header, with any payload length field.
+fn header(length: u16, request: u16) -> Vec<u8> {
+    let mut header = b"STRY".to_vec();
+    header.extend_from_slice(&length.to_be_bytes());
+    header.extend_from_slice(&request.to_be_bytes());
+    header
+}
+
+fn expect_payload(payload: &[u8], expected: &[u8]) -> Result<(), String> {
+    if payload == expected {
+        Ok(())
+    } else {
+        Err(format!(
+            "expected payload {:?}, got {:?}",
+            String::from_utf8_lossy(expected),
+            String::from_utf8_lossy(payload)
+        ))
+    }
+}
+
+/// Returns the received bytes field of a GetStats payload.
+fn stats_received(payload: &[u8]) -> Result<u32, String> {
+    if payload.len() != 9 {
+        return Err(format!(
+            "expected 9 byte stats, got {} bytes",
+            payload.len()
+        ));
+    }
+    if payload[8] > 100 {
+        return Err(format!("compression ratio {}% is over 100%", payload[8]));
+    }
+    Ok(u32::from_be_bytes([
+        payload[0], payload[1], payload[2], payload[3],
+    ]))
+}
+
+/// One check, run on its own connection.
+struct Check {
+    name: &'static str,
+    skip: fn(&Args) -> Option<&'static str>, // why the check can't run with these args
+    // the status code only this check gets from the server, reported as skipped with it
+    requires: Option<u16>,
+    run: fn(&mut Connection, &Args) -> Result<(), String>,
+}
+
+fn never(_: &Args) -> Option<&'static str> {
+    None
+}
+
+fn resets(args: &Args) -> Option<&'static str> {
+    if args.no_reset {
+        Some("resets the stats")
+    } else {
+        None
+    }
+}
+
+/// How long to wait for a connection over the limit to be rejected.
+const REJECT_WAIT: Duration = Duration::from_millis(500);
+
+/// How many times to try a new connection while the server releases held ones.
+const RELEASE_TRIES: u32 = 20;
+
+/// Closes connections held open to the server and waits until it accepts a new one, so
+/// a later check isn't rejected while the server is still releasing them.
+fn release(held: Vec<Connection>, args: &Args) -> Result<(), String> {
+    drop(held);
+    for _ in 0..RELEASE_TRIES {
+        let mut probe = Connection::open(&args.addr, REJECT_WAIT)
+            .map_err(|error| format!("connect after release failed: {}", error))?;
+        probe.send(&packet(1, b""))?;
+        match probe.receive() {
+            Ok(response) if response.status == 38 => thread::sleep(REJECT_WAIT / 5),
+            _ => return Ok(()),
+        }
+    }
+    Err(format!(
+        "server_busy after releasing connections for {} tries",
+        RELEASE_TRIES
+    ))
+}
+
+const CHECKS: &[Check] = &[
+    Check {
+        name: "ping",
+        skip: never,
+        requires: None,
+        run: |conn, _| expect_payload(&conn.expect(&packet(1, b""), 0)?, b""),
+    },
+    Check {
+        name: "ping_with_payload",
+        skip: never,
+        requires: None,
+        run: |conn, _| {
+            conn.expect(&packet(1, b"a"), 34)?;
+            conn.expect_ping()
+        },
+    },
+    Check {
+        name: "get_stats",
+        skip: never,
+        requires: None,
+        run: |conn, _| {
+            let received = stats_received(&conn.expect(&packet(2, b""), 0)?)?;
+            if received < 8 {
+                return Err(format!(
+                    "received {} bytes is less than this request",
+                    received
+                ));
+            }
+            Ok(())
+        },
+    },
+    Check {
+        name: "get_stats_with_payload",
+        skip: never,
+        requires: None,
+        run: |conn, _| {
+            conn.expect(&packet(2, b"a"), 34)?;
+            conn.expect_ping()
+        },
+    },
+    Check {
+        name: "reset_stats",
+        skip: resets,
+        requires: None,
+        run: |conn, _| {
+            conn.expect(&packet(4, &[b'a'; 100]), 0)?;
+            let before = stats_received(&conn.expect(&packet(2, b""), 0)?)?;
+            expect_payload(&conn.expect(&packet(3, b""), 0)?, b"")?;
+            let after = stats_received(&conn.expect(&packet(2, b""), 0)?)?;
+            if after >= before {
+                return Err(format!(
+                    "received {} bytes before reset and {} after",
+                    before, after
+                ));
+            }
+            Ok(())
+        },
+    },
+    Check {
+        name: "reset_stats_with_payload",
+        skip: never,
+        requires: None,
+        run: |conn, _| {
+            conn.expect(&packet(3, b"a"), 34)?;
+            conn.expect_ping()
+        },
+    },
+    Check {
+        name: "compress",
+        skip: never,
+        requires: None,
+        run: |conn, _| expect_payload(&conn.expect(&packet(4, b"aaabbbcccc"), 0)?, b"3a3b4c"),
+    },
+    Check {
+        name: "compress_short_runs",
+        skip: never,
+        requires: None,
+        run: |conn, _| expect_payload(&conn.expect(&packet(4, b"abccdddd"), 0)?, b"abcc4d"),
+    },
+    Check {
+        name: "compress_max_payload",
+        skip: never,
+        requires: None,
+        run: |conn, _| {
+            // every server must accept payloads of at least 4 KiB
+            expect_payload(&conn.expect(&packet(4, &[b'z'; 4096]), 0)?, b"4096z")
+        },
+    },
+    Check {
+        name: "compress_empty",
+        skip: never,
+        requires: None,
+        run: |conn, _| {
+            conn.expect(&packet(4, b""), 33)?;
+            conn.expect_ping()
+        },
+    },
+    Check {
+        name: "compress_non_ascii",
+        skip: never,
+        requires: None,
+        run: |conn, _| {
+            conn.expect(&packet(4, "ab☺".as_bytes()), 35)?;
+            conn.expect_ping()
+        },
+    },
+    Check {
+        name: "compress_non_alphabetic",
+        skip: never,
+        requires: None,
+        run: |conn, _| {
+            conn.expect(&packet(4, b"ab1"), 36)?;
+            conn.expect_ping()
+        },
+    },
+    Check {
+        name: "compress_uppercase",
+        skip: never,
+        requires: None,
+        run: |conn, _| {
+            conn.expect(&packet(4, b"abC"), 37)?;
+            conn.expect_ping()
+        },
+    },
+    Check {
+        name: "oversized_payload",
+        skip: never,
+        requires: None,
+        run: |conn, _| {
+            // over the largest max payload a server may have, so no payload is sent
+            conn.expect(&header(0x8000, 4), 2)?;
+            conn.expect_ping()
+        },
+    },
+    Check {
+        name: "payload_length_shorter_than_payload",
+        skip: never,
+        requires: None,
+        run: |conn, _| {
+            // the extra byte is garbage before the next packet
+            let mut bytes = header(2, 4);
+            bytes.extend_from_slice(b"aaa");
+            expect_payload(&conn.expect(&bytes, 0)?, b"aa")?;
+            conn.expect_ping()
+        },
+    },
+    Check {
+        name: "get_histograms",
+        skip: never,
+        requires: None,
+        run: |conn, _| conn.expect(&packet(5, b""), 0).map(|_| ()),
+    },
+    Check {
+        name: "authenticate_challenge",
+        skip: never,
+        requires: None,
+        run: |conn, _| {
+            // unsupported when the server has no keys
+            conn.send(&packet(6, b""))?;
+            let response = conn.receive()?;
+            match (response.status, response.payload.len()) {
+                (0, 32) | (3, 0) => Ok(()),
+                (status, len) => Err(format!(
+                    "expected a 32 byte challenge or unsupported_request_type, \
+                     got status {} {} with {} bytes",
+                    status,
+                    StatusCode::name(status),
+                    len
+                )),
+            }
+        },
+    },
+    Check {
+        name: "authenticate_without_challenge",
+        skip: never,
+        requires: None,
+        run: |conn, _| {
+            conn.send(&packet(6, &[0; 32]))?;
+            match conn.receive()?.status {
+                41 | 3 => conn.expect_ping(),
+                status => Err(format!(
+                    "expected authentication_failed or unsupported_request_type, got {} {}",
+                    status,
+                    StatusCode::name(status)
+                )),
+            }
+        },
+    },
+    Check {
+        name: "unsupported_request",
+        skip: never,
+        requires: None,
+        run: |conn, _| {
+            conn.expect(&packet(99, b""), 3)?;
+            conn.expect(&packet(0, b""), 3)?;
+            conn.expect_ping()
+        },
+    },
+    Check {
+        name: "garbage_before_header",
+        skip: never,
+        requires: None,
+        run: |conn, _| {
+            conn.send(b"garbage")?;
+            conn.expect_ping()
+        },
+    },
+    Check {
+        name: "partial_header_in_garbage",
+        skip: never,
+        requires: None,
+        run: |conn, _| {
+            conn.send(b"STRSTSTR")?;
+            conn.expect_ping()
+        },
+    },
+    Check {
+        name: "split_packet",
+        skip: never,
+        requires: None,
+        run: |conn, _| {
+            for byte in packet(4, b"aaaa") {
+                conn.send(&[byte])?;
+                thread::sleep(Duration::from_millis(5));
+            }
+            expect_payload(&conn.expect_response(0)?, b"4a")
+        },
+    },
+    Check {
+        name: "pipelined_requests",
+        skip: never,
+        requires: None,
+        run: |conn, _| {
+            let mut bytes = packet(1, b"");
+            bytes.extend(packet(4, b"bbbb"));
+            bytes.extend(packet(2, b""));
+            conn.send(&bytes)?;
+            expect_payload(&conn.expect_response(0)?, b"")?;
+            expect_payload(&conn.expect_response(0)?, b"4b")?;
+            stats_received(&conn.expect_response(0)?).map(|_| ())
+        },
+    },
+    Check {
+        name: "authentication_failed",
+        skip: |args| match (&args.key, &args.user_key) {
+            (None, None) => Some("needs --key or --user-key"),
+            _ => None,
+        },
+        requires: Some(41),
+        run: |conn, _| {
+            let challenge = conn.expect(&packet(6, b""), 0)?;
+            conn.expect(&packet(6, &sign("not a key", &challenge)), 41)?;
+            conn.expect_ping()
+        },
+    },
+    Check {
+        name: "permission_denied",
+        skip: |args| match args.user_key {
+            None => Some("needs --user-key"),
+            Some(_) => None,
+        },
+        requires: Some(42),
+        run: |conn, args| {
+            // users can't reset every client's stats
+            conn.authenticate(args.user_key.as_deref().unwrap_or_default())
+                .map_err(|error| format!("authenticate as user failed: {}", error))?;
+            conn.expect(&packet(3, b""), 42)?;
+            conn.expect_ping()
+        },
+    },
+    Check {
+        name: "timeout",
+        skip: |args| match args.packet_timeout {
+            None => Some("needs --packet-timeout"),
+            Some(_) => None,
+        },
+        requires: Some(39),
+        run: |conn, args| {
+            // stall a partial packet until the server gives up on it
+            let mut bytes = header(4, 4);
+            bytes.extend_from_slice(b"aa");
+            conn.send(&bytes)?;
+            thread::sleep(Duration::from_secs(args.packet_timeout.unwrap_or_default()));
+            conn.expect_response(39)?;
+            conn.expect_ping()
+        },
+    },
+    Check {
+        name: "rate_limited",
+        skip: |args| match args.rate_limit {
+            None => Some("needs --rate-limit"),
+            Some(_) => None,
+        },
+        requires: Some(40),
+        run: |conn, args| {
+            // a client may only send a second worth of requests at once, and twice that
+            // arrive well within a second, before the limit allows as many again
+            let count = args.rate_limit.unwrap_or_default() as usize * 2 + 1;
+            conn.send(&packet(1, b"").repeat(count))?;
+            let mut limited = false;
+            for _ in 0..count {
+                let response = conn.receive()?;
+                match (response.status, response.payload.len()) {
+                    (0, 0) => {}
+                    (40, 4) => limited = true,
+                    (status, len) => {
+                        return Err(format!(
+                            "expected ok or rate_limited with a retry time, \
+                             got status {} {} with {} bytes",
+                            status,
+                            StatusCode::name(status),
+                            len
+                        ))
+                    }
+                }
+            }
+            if !limited {
+                return Err(format!("no rate_limited after {} pings", count));
+            }
+
+            // leave the client within its limit for any later run
+            thread::sleep(Duration::from_secs(1));
+            conn.expect_ping()
+        },
+    },
+    Check {
+        name: "server_busy",
+        skip: |args| match args.max_connections {
+            None => Some("needs --max-connections"),
+            Some(_) => None,
+        },
+        requires: Some(38),
+        run: |conn, args| {
+            // this connection is one, so the last one opened is over the limit. Others
+            // may still be open, so a rejected connection ends the check early. It's the
+            // last check, so only a later run connects while the server releases them
+            let mut held = Vec::new();
+            for _ in 0..args.max_connections.unwrap_or_default() {
+                let mut extra = Connection::open(&args.addr, REJECT_WAIT)
+                    .map_err(|error| format!("connect failed: {}", error))?;
+                let response = extra.receive();
+                conn.statuses.extend(&extra.statuses);
+                match response {
+                    Ok(response) if response.status == 38 => return release(held, args),
+                    Ok(response) => {
+                        return Err(format!(
+                            "expected nothing or server_busy, got {} {}",
+                            response.status,
+                            StatusCode::name(response.status)
+                        ))
+                    }
+                    Err(_) => held.push(extra), // accepted, waiting for a request
+                }
+            }
+            let count = held.len();
+            release(held, args)?;
+            Err(format!("no server_busy after {} more connections", count))
+        },
+    },
+];
+
+fn main() {
+    let args = Args::from_args();
+    let timeout = Duration::from_secs(args.timeout);
+
+    let (mut passed, mut failed, mut skipped) = (0, 0, 0);
+    let mut statuses = BTreeSet::new();
+    let mut skipped_statuses = Vec::new();
+    for check in CHECKS {
+        if let Some(reason) = (check.skip)(&args) {
+            println!("SKIP {}: {}", check.name, reason);
+            skipped_statuses.extend(check.requires);
+            skipped += 1;
+            continue;
+        }
+
+        let mut conn = match Connection::open(&args.addr, timeout) {
+            Ok(conn) => conn,
+            Err(error) => {
+                eprintln!("error: {}: {}", args.addr, error);
+                process::exit(EXIT_UNAVAILABLE);
+            }
+        };
+        let result = match &args.key {
+            Some(key) => conn
+                .authenticate(key)
+                .map_err(|error| format!("authenticate failed: {}", error)),
+            None => Ok(()),
+        };
+
+        match result.and_then(|_| (check.run)(&mut conn, &args)) {
+            Ok(()) => {
+                println!("PASS {}", check.name);
+                passed += 1;
+            }
+            Err(error) => {
+                println!("FAIL {}: {}", check.name, error);
+                failed += 1;
+            }
+        }
+        statuses.extend(conn.statuses);
+    }
+
+    // some status codes depend on the server's config, so their checks were skipped
+    let missing: Vec<_> = STATUS_CODES
+        .iter()
+        .filter(|status| !statuses.contains(status))
+        .map(|status| {
+            let name = StatusCode::name(*status);
+            if skipped_statuses.contains(status) {
+                format!("{} {} (skipped)", status, name)
+            } else {
+                format!("{} {}", status, name)
+            }
+        })
+        .collect();
+    if !missing.is_empty() {
+        println!("status codes not exercised: {}", missing.join(", "));
+    }
+    println!("{} passed, {} failed, {} skipped", passed, failed, skipped);
+
+    if failed > 0 {
+        process::exit(1);
+    }
+}
@@ -0,0 +1,370 @@
+use super::message::{RequestCode, StatusCode};
+use super::packet::ClientCodec;
+
+use bytes::{Buf, BytesMut};
+use futures::sink::SinkExt;
+use futures::stream::StreamExt;
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use std::time::Duration;
+use std::{error, fmt, io};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpStream, UnixStream};
+use tokio::time;
+use tokio_util::codec::Framed;
+
+/// A TCP or Unix domain socket connection to the server.
+trait Connection: AsyncRead + AsyncWrite + Unpin + Send + Sync {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Sync> Connection for T {}
+
+/// Why a request didn't get an ok response.
+#[derive(Debug)]
+pub enum Error {
+    Status(StatusCode), // the server responded with an error status code
+    Io(io::Error),
+    Timeout,                       // no response within the timeout
+    Closed,                        // the server closed the connection without a response
+    InvalidResponse(&'static str), // an ok response with an unexpected payload
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Status(status) => match status {
+                StatusCode::Ok(_) => write!(f, "ok"),
+                StatusCode::UnknownError => write!(f, "unknown server error"),
+                StatusCode::MessageTooLarge => write!(f, "payload is over the max payload length"),
+                StatusCode::UnsupportedRequestType => write!(f, "request type is not supported"),
+                StatusCode::EmptyBuffer => write!(f, "payload is empty"),
+                StatusCode::NonEmptyBuffer => write!(f, "request does not take a payload"),
+                StatusCode::NonAscii => write!(f, "payload is not ascii"),
+                StatusCode::NonAlphabetic => write!(f, "payload is not all letters"),
+                StatusCode::NonLowerCase => write!(f, "payload is not all lowercase"),
+                StatusCode::ServerBusy => write!(f, "server is busy"),
+                StatusCode::Timeout => write!(f, "server timed out waiting for the request"),
+                StatusCode::RateLimited(retry_after) => {
+                    write!(f, "rate limited, retry after {} ms", retry_after)
+                }
+                StatusCode::AuthenticationFailed => write!(f, "authentication failed"),
+                StatusCode::PermissionDenied => write!(f, "permission denied"),
+                StatusCode::IoError(kind) => write!(f, "server IO error: {:?}", kind),
+            },
+            Error::Io(error) => write!(f, "{}", error),
+            Error::Timeout => write!(f, "timed out waiting for the server"),
+            Error::Closed => write!(f, "server closed the connection"),
+            Error::InvalidResponse(reason) => write!(f, "invalid response: {}", reason),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+impl error::Error for Error {}
+
+/// The payload of a GetStats response.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Stats {
+    pub received: u32, // packet bytes received by the server, including this request
+    pub sent: u32,     // packet bytes sent by the server
+    pub ratio: u8,     // compressed payload bytes as a percentage of uncompressed bytes
+}
+
+/// A client that sends one request at a time over a single connection. The connection
+/// is opened by the first request and reused by the next ones. A request on a connection
+/// the server has since closed is retried once on a new connection. After any other IO
+/// error or a timeout, the connection is dropped and the next request opens a new one.
+/// New connections are authenticated again with the last key passed to authenticate.
+pub struct Client {
+    addr: String, // host and port, or unix:path
+    timeout: Duration,
+    key: Option<String>,
+    connection: Option<Framed<Box<dyn Connection>, ClientCodec>>,
+}
+
+impl Client {
+    /// Creates a client for a host and port, ie. `localhost:4000`, or a Unix domain
+    /// socket path prefixed with `unix:`. Connecting and each request must finish
+    /// within timeout.
+    pub fn new(addr: &str, timeout: Duration) -> Client {
+        Client {
+            addr: addr.to_string(),
+            timeout,
+            key: None,
+            connection: None,
+        }
+    }
+
+    /// Creates a client and opens its connection, to find out right away if the server
+    /// can't be reached.
+    pub async fn connect(addr: &str, timeout: Duration) -> Result<Client, Error> {
+        let mut client = Client::new(addr, timeout);
+        let connection = client.open().await?;
+        client.connection = Some(connection);
+        Ok(client)
+    }
+
+    pub async fn ping(&mut self) -> Result<(), Error> {
+        self.request(RequestCode::Ping).await.and_then(empty)
+    }
+
+    pub async fn get_stats(&mut self) -> Result<Stats, Error> {
+        let mut payload = self.request(RequestCode::GetStats).await?;
+        if payload.len() != 9 {
+            return Err(Error::InvalidResponse("stats payload is not 9 bytes"));
+        }
+
+        Ok(Stats {
+            received: payload.get_u32(), // uses big-endian order
+            sent: payload.get_u32(),
+            ratio: payload.get_u8(),
+        })
+    }
+
+    pub async fn reset_stats(&mut self) -> Result<(), Error> {
+        self.request(RequestCode::ResetStats).await.and_then(empty)
+    }
+
+    /// Returns the compressed payload.
+    pub async fn compress(&mut self, payload: &[u8]) -> Result<BytesMut, Error> {
+        self.request(RequestCode::Compress(BytesMut::from(payload)))
+            .await
+    }
+
+    /// Signs a challenge from the server with a shared secret key. Returns the role
+    /// value of the key, 1 for a user or 2 for an admin.
+    pub async fn authenticate(&mut self, key: &str) -> Result<u8, Error> {
+        let challenge = self.request(RequestCode::Authenticate(BytesMut::new()));
+        let signature = sign(key, &challenge.await?);
+        let role = self.request(RequestCode::Authenticate(signature)).await?;
+        if role.len() != 1 {
+            return Err(Error::InvalidResponse("role payload is not 1 byte"));
+        }
+
+        self.key = Some(key.to_string()); // to authenticate new connections
+        Ok(role[0])
+    }
+
+    /// Sends any request and returns the payload of an ok response.
+    pub async fn request(&mut self, request: RequestCode) -> Result<BytesMut, Error> {
+        let result = match time::timeout(self.timeout, self.transceive(request)).await {
+            Ok(result) => result,
+            Err(_) => Err(Error::Timeout),
+        };
+
+        match result {
+            Ok(StatusCode::Ok(payload)) => Ok(payload),
+            Ok(status) => Err(Error::Status(status)),
+            Err(error) => {
+                // a late response would be mistaken for the response to the next
+                // request, so don't reuse the connection
+                self.connection = None;
+                Err(error)
+            }
+        }
+    }
+
+    async fn transceive(&mut self, request: RequestCode) -> Result<StatusCode, Error> {
+        if let Some(connection) = &mut self.connection {
+            // the server may have closed the connection since the last request, ie.
+            // after its idle timeout, so retry those requests on a new connection. A
+            // server closing an idle connection sends Timeout first, which is read as the
+            // response, since this client never leaves a packet partially sent
+            match transceive(connection, request.clone()).await {
+                Ok(StatusCode::Timeout) => {}
+                Err(error) if is_closed(&error) => {}
+                result => return result,
+            }
+        }
+
+        let connection = self.connection.insert(self.open().await?);
+        transceive(connection, request).await
+    }
+
+    /// Opens a new connection, and authenticates it when a key was set.
+    async fn open(&self) -> Result<Framed<Box<dyn Connection>, ClientCodec>, Error> {
+        let open = async {
+            let socket: Box<dyn Connection> = match self.addr.strip_prefix("unix:") {
+                Some(path) => Box::new(UnixStream::connect(path).await?),
+                None => Box::new(TcpStream::connect(self.addr.as_str()).await?),
+            };
+            let mut connection = Framed::new(socket, ClientCodec::new());
+
+            if let Some(key) = &self.key {
+                let challenge = RequestCode::Authenticate(BytesMut::new());
+                let challenge = ok(transceive(&mut connection, challenge).await?)?;
+                let signature = RequestCode::Authenticate(sign(key, &challenge));
+                ok(transceive(&mut connection, signature).await?)?;
+            }
+            Ok(connection)
+        };
+
+        match time::timeout(self.timeout, open).await {
+            Ok(result) => result,
+            Err(_) => Err(Error::Timeout),
+        }
+    }
+}
+
+async fn transceive(
+    connection: &mut Framed<Box<dyn Connection>, ClientCodec>,
+    request: RequestCode,
+) -> Result<StatusCode, Error> {
+    connection.send(request).await?;
+    match connection.next().await {
+        Some(result) => Ok(result?),
+        None => Err(Error::Closed),
+    }
+}
+
+fn is_closed(error: &Error) -> bool {
+    match error {
+        Error::Closed => true,
+        Error::Io(error) => matches!(
+            error.kind(),
+            io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset
+        ),
+        _ => false,
+    }
+}
+
+fn ok(status: StatusCode) -> Result<BytesMut, Error> {
+    match status {
+        StatusCode::Ok(payload) => Ok(payload),
+        status => Err(Error::Status(status)),
+    }
+}
+
+fn empty(payload: BytesMut) -> Result<(), Error> {
+    if payload.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::InvalidResponse("unexpected payload"))
+    }
+}
+
+/// Signs an Authenticate challenge with a shared secret key using HMAC-SHA256.
+pub fn sign(key: &str, challenge: &[u8]) -> BytesMut {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("any key length");
+    mac.update(challenge);
+    BytesMut::from(&mac.finalize().into_bytes()[..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compress::Compressor;
+    use crate::packet::PacketCodec;
+    use bytes::BufMut;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+
+    /// Starts a server that answers requests like the real one, but closes each
+    /// connection after max_requests, sending Timeout first like an idle timeout.
+    /// Returns its address and a count of accepted connections.
+    async fn server(max_requests: usize) -> (String, Arc<AtomicUsize>) {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let count = accepted.clone();
+
+        tokio::spawn(async move {
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                count.fetch_add(1, Ordering::SeqCst);
+                let mut stream =
+                    Framed::new(socket, PacketCodec::new_with_max_payload(4096).unwrap());
+                for _ in 0..max_requests {
+                    let status = match stream.next().await {
+                        Some(Ok(RequestCode::Ping)) => StatusCode::Ok(BytesMut::new()),
+                        Some(Ok(RequestCode::GetStats)) => {
+                            let mut stats = BytesMut::new();
+                            stats.put_u32(8);
+                            stats.put_u32(16);
+                            stats.put_u8(50);
+                            StatusCode::Ok(stats)
+                        }
+                        Some(Ok(RequestCode::Compress(payload))) => {
+                            match Compressor::new().compress(payload) {
+                                Ok(payload) => StatusCode::Ok(payload),
+                                Err(status) => status,
+                            }
+                        }
+                        Some(Ok(_)) => StatusCode::PermissionDenied,
+                        Some(Err(status)) => status,
+                        None => break,
+                    };
+                    stream.send(status).await.unwrap();
+                }
+                let _ = stream.send(StatusCode::Timeout).await; // STRY\0\0\0\x27
+            }
+        });
+
+        (addr, accepted)
+    }
+
+    #[tokio::test]
+    async fn requests() {
+        let (addr, accepted) = server(usize::MAX).await;
+        let mut client = Client::connect(&addr, Duration::from_secs(1))
+            .await
+            .unwrap();
+
+        client.ping().await.unwrap();
+        assert_eq!(
+            client.get_stats().await.unwrap(),
+            Stats {
+                received: 8,
+                sent: 16,
+                ratio: 50
+            }
+        );
+        assert_eq!(client.compress(b"aaaab").await.unwrap(), "4ab");
+        match client.compress(b"ABC").await {
+            Err(Error::Status(StatusCode::NonLowerCase)) => {}
+            result => panic!("unexpected {:?}", result),
+        }
+        match client.reset_stats().await {
+            Err(Error::Status(StatusCode::PermissionDenied)) => {}
+            result => panic!("unexpected {:?}", result),
+        }
+
+        // every request used the same connection
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn reconnect() {
+        let (addr, accepted) = server(1).await;
+        let mut client = Client::new(&addr, Duration::from_secs(1));
+        assert_eq!(accepted.load(Ordering::SeqCst), 0);
+
+        // the server times out the connection after each request
+        client.ping().await.unwrap();
+        client.ping().await.unwrap();
+        client.ping().await.unwrap();
+        assert_eq!(accepted.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn timeout() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let _connection = listener.accept().await; // never respond
+            time::delay_for(Duration::from_secs(10)).await;
+        });
+
+        let mut client = Client::new(&addr, Duration::from_millis(100));
+        match client.ping().await {
+            Err(Error::Timeout) => {}
+            result => panic!("unexpected {:?}", result),
+        }
+    }
+}
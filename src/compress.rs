@@ -2,11 +2,18 @@ use super::message::StatusCode;
 
 use bytes::BytesMut;
 
+/// Compresses lowercase ascii payloads, counting the bytes before and after compression.
 pub struct Compressor {
     before: usize,
     after: usize,
 }
 
+impl Default for Compressor {
+    fn default() -> Compressor {
+        Compressor::new()
+    }
+}
+
 impl Compressor {
     pub fn new() -> Compressor {
         Compressor {
@@ -15,6 +22,8 @@ impl Compressor {
         }
     }
 
+    /// Returns the number of bytes before and after compression, of successfully
+    /// compressed buffers only.
     pub fn get_stats(&self) -> (usize, usize) {
         (self.before, self.after)
     }
@@ -92,6 +101,61 @@ impl Compressor {
 
         Ok(buffer.split_to(end))
     }
+
+    /// Reverses compress, repeating each letter that follows a number that many times.
+    ///
+    /// Letters must be lowercase ascii like compress accepts, and a number must be followed
+    /// by a letter. Output longer than max_len is MessageTooLarge, since a short buffer
+    /// can hold a huge number. Doesn't change the stats.
+    pub fn decompress(buffer: &[u8], max_len: usize) -> Result<BytesMut, StatusCode> {
+        if buffer.is_empty() {
+            return Err(StatusCode::EmptyBuffer);
+        }
+
+        let mut output = BytesMut::with_capacity(buffer.len());
+        let mut count: Option<usize> = None;
+
+        for &byte in buffer {
+            let current = byte as char;
+
+            if let Some(digit) = current.to_digit(10) {
+                // a count too large to fit in memory is too large to decompress
+                count = count
+                    .unwrap_or(0)
+                    .checked_mul(10)
+                    .and_then(|count| count.checked_add(digit as usize));
+                if count.is_none() {
+                    return Err(StatusCode::MessageTooLarge);
+                }
+                continue;
+            }
+
+            // input check
+            if !current.is_ascii() {
+                return Err(StatusCode::NonAscii);
+            }
+            if !current.is_ascii_alphabetic() {
+                return Err(StatusCode::NonAlphabetic);
+            }
+            if !current.is_ascii_lowercase() {
+                return Err(StatusCode::NonLowerCase);
+            }
+
+            let repeat = count.take().unwrap_or(1);
+            let len = match output.len().checked_add(repeat) {
+                Some(len) if len <= max_len => len,
+                _ => return Err(StatusCode::MessageTooLarge),
+            };
+            output.resize(len, byte);
+        }
+
+        // a number at the end is missing its letter
+        if count.is_some() {
+            return Err(StatusCode::NonAlphabetic);
+        }
+
+        Ok(output)
+    }
 }
 
 #[cfg(test)]
@@ -198,4 +262,67 @@ mod tests {
             Err(StatusCode::EmptyBuffer)
         );
     }
+
+    #[test]
+    fn decompress_5a6b3abb() {
+        assert_eq!(
+            Compressor::decompress(b"5a6b3abb", 16),
+            Ok(BytesMut::from("aaaaabbbbbbaaabb"))
+        );
+    }
+
+    #[test]
+    fn decompress_round_trip() {
+        let mut compressor = Compressor::new();
+        let input = BytesMut::from("aaaaaaaaaaaabcccdddddddddddddddddddddz");
+        let compressed = compressor.compress(input.clone()).unwrap();
+        assert_eq!(compressed, BytesMut::from("12ab3c21dz"));
+        assert_eq!(Compressor::decompress(&compressed, 38), Ok(input));
+        assert_eq!(compressor.get_stats(), (38, 10)); // decompress doesn't count
+    }
+
+    #[test]
+    fn decompress_invalid() {
+        assert_eq!(
+            Compressor::decompress(b"", 16),
+            Err(StatusCode::EmptyBuffer)
+        );
+        assert_eq!(
+            Compressor::decompress(b"3a4", 16),
+            Err(StatusCode::NonAlphabetic)
+        );
+        assert_eq!(
+            Compressor::decompress(b"3a-b", 16),
+            Err(StatusCode::NonAlphabetic)
+        );
+        assert_eq!(
+            Compressor::decompress(b"3A", 16),
+            Err(StatusCode::NonLowerCase)
+        );
+        assert_eq!(
+            Compressor::decompress("3☺".as_bytes(), 16),
+            Err(StatusCode::NonAscii)
+        );
+    }
+
+    #[test]
+    fn decompress_too_large() {
+        assert_eq!(
+            Compressor::decompress(b"5a6b3abb", 15),
+            Err(StatusCode::MessageTooLarge)
+        );
+        for buffer in [
+            &b"99999999999999999999999a"[..],
+            b"18446744073709551615a",
+            b"9a18446744073709551610a",
+            b"99999999999a",
+        ]
+        .iter()
+        {
+            assert_eq!(
+                Compressor::decompress(buffer, 1 << 16),
+                Err(StatusCode::MessageTooLarge)
+            );
+        }
+    }
 }
@@ -0,0 +1,15 @@
+//! The packet protocol and compression used by the compression service.
+//!
+//! [`packet::PacketCodec`] decodes request packets into [`message::RequestCode`]s and
+//! encodes [`message::StatusCode`]s into response packets. Use it with a
+//! `tokio_util::codec::Framed` stream to serve requests, and [`compress::Compressor`]
+//! to answer Compress requests. [`client::Client`] sends requests to a server.
+//!
+//! [`server::Server`] is the compression service itself, accepting clients from
+//! [`server::listener::Listener`]s with the limits of a [`server::config::Config`].
+
+pub mod client;
+pub mod compress;
+pub mod message;
+pub mod packet;
+pub mod server;
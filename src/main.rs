@@ -1,124 +1,235 @@
-mod compress;
-mod message;
-mod packet;
-
-use compress::Compressor;
-use message::{RequestCode, StatusCode};
-use packet::PacketCodec;
-
-use bytes::{BufMut, BytesMut};
-use futures::sink::SinkExt;
+use compression_service::server::config::{Args, Config};
+use compression_service::server::listener::Listener;
+use compression_service::server::ratelimit::RateLimiter;
+use compression_service::server::stats::Stats;
+use compression_service::server::{
+    logger, metrics, persist, replay, shutdown, systemd, tls, Server,
+};
+
+use futures::future;
 use std::sync::Arc;
-use std::{error, io};
+use std::time::Duration;
+use std::{io, process};
+use structopt::StructOpt;
 use tokio::net::TcpListener;
-use tokio::stream::StreamExt;
-use tokio::sync::Mutex;
-use tokio_util::codec::Framed;
-
-/// Global server stats.
-struct Stats {
-    received: usize,
-    sent: usize,
-    before: usize,
-    after: usize,
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{watch, Mutex};
+
+fn main() -> io::Result<()> {
+    // take what systemd passed before the runtime starts its threads, since changing the
+    // environment while another thread reads it is a data race
+    let listen_fds = systemd::listen_fds();
+    let notify_socket = systemd::notify_socket();
+
+    let mut runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(run(listen_fds, notify_socket))
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn error::Error>> {
-    let mut listener = TcpListener::bind(":::4000").await?;
+async fn run(
+    listen_fds: io::Result<Vec<systemd::ListenFd>>,
+    notify_socket: Option<String>,
+) -> io::Result<()> {
+    // report invalid options before doing anything else
+    let args = Args::from_args();
+    let config = match Config::load(&args) {
+        Ok(config) => Arc::new(config),
+        Err(error) => {
+            eprintln!("error: {}", error);
+            process::exit(2);
+        }
+    };
+
+    // replay a capture without logging or serving any clients
+    if let Some(path) = &args.replay {
+        process::exit(replay::run(path, config).await);
+    }
+
+    if let Err(error) = logger::init(
+        config.log_level,
+        config.log_file.as_deref(),
+        config.log_max_size,
+        config.log_max_files,
+    ) {
+        eprintln!("error: {}", error);
+        process::exit(2);
+    }
+    log::info!(
+        "max payload {} bytes, max connections {}, packet timeout {}s, idle timeout {}s",
+        config.max_payload,
+        config.max_connections,
+        config.packet_timeout(),
+        config.idle_timeout
+    );
+
+    // create global stats, restoring any saved by a previous run
+    let stats = match &config.stats_file {
+        Some(path) => persist::restore(path),
+        None => Stats::new(),
+    };
+    let stats = Arc::new(Mutex::new(stats));
+
+    // optionally serve global stats over HTTP for Prometheus
+    if let Some(addr) = config.metrics {
+        let listener = TcpListener::bind(addr).await?;
+        log::info!("serving metrics on http://{}/metrics", addr);
+        tokio::spawn(metrics::serve(listener, stats.clone()));
+    }
+
+    // load the certificate once for every tls listener
+    let tls = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => Some(tls::acceptor(cert, key, config.tls_client_ca.as_deref())?),
+        _ => None,
+    };
+
+    // use the sockets passed by systemd socket activation instead of the listen option
+    let inherited = listen_fds?;
+    let mut listeners = Vec::with_capacity(config.listen.len().max(inherited.len()));
+    for systemd::ListenFd { fd, name } in inherited {
+        // sockets named "tls" with FileDescriptorName= accept tls clients
+        let tls = match (name.as_str(), &tls) {
+            ("tls", None) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "inherited tls socket without a tls_cert and tls_key",
+                ))
+            }
+            ("tls", Some(acceptor)) => Some(acceptor),
+            _ => None,
+        };
+        let listener = unsafe { Listener::from_fd(fd, tls)? }; // fds are ours after listen_fds
+        log::info!("listening on {} from systemd", listener.local_addr());
+        listeners.push(listener);
+    }
+
+    // bind every listener before accepting any clients so bad addresses fail at startup
+    if listeners.is_empty() {
+        for addr in &config.listen {
+            listeners.push(Listener::bind(addr, tls.as_ref()).await?);
+            log::info!("listening on {}", addr);
+        }
+    }
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut hangup = signal(SignalKind::hangup())?;
+    let (trigger, shutdown) = shutdown::channel();
+
+    if let (Some(path), true) = (&config.stats_file, config.stats_interval > 0) {
+        let interval = Duration::from_secs(config.stats_interval);
+        tokio::spawn(persist::save_every(
+            path.clone(),
+            interval,
+            stats.clone(),
+            shutdown.clone(),
+        ));
+    }
 
-    // create global stats
-    let stats = Arc::new(Mutex::new(Stats {
-        received: 0,
-        sent: 0,
-        before: 0,
-        after: 0,
-    }));
+    // replaced on SIGHUP, new connections use the latest config
+    let (config_tx, config_rx) = watch::channel(config.clone());
+    let server = Server::new(stats.clone(), config_rx.clone());
 
+    let accept_loops = listeners
+        .into_iter()
+        .map(|listener| server.clone().serve(listener, shutdown.clone()));
+    let mut accepting = Box::pin(future::try_join_all(accept_loops));
+    notify_systemd(notify_socket.as_deref(), "READY=1");
+
+    // accept clients until a signal arrives, then drop the listeners to stop accepting
     loop {
-        let (socket, _addr) = listener.accept().await?;
-        let stats = stats.clone(); // local reference to global stats
-
-        tokio::spawn(async move {
-            // create packet codec with 16 KiB max payload length
-            let mut stream = Framed::new(socket, PacketCodec::new_with_max_payload(1 << 14));
-            let mut compressor = Compressor::new();
-
-            loop {
-                {
-                    // get local stats
-                    let (received, sent) = stream.codec().get_stats();
-                    let (before, after) = compressor.get_stats();
-
-                    // update global stats
-                    let mut stats = stats.lock().await;
-                    stats.received += received;
-                    stats.sent += sent;
-                    stats.before += before;
-                    stats.after += after;
-
-                    // reset local stats
-                    stream.codec_mut().reset_stats();
-                    compressor.reset_stats();
-                } // <- drop stats lock here
-
-                match stream.next().await {
-                    // process request code
-                    Some(Ok(request)) => {
-                        match request {
-                            RequestCode::Ping => {
-                                stream.send(StatusCode::Ok(BytesMut::new())).await?;
-                            }
-                            RequestCode::GetStats => {
-                                // should response be local stats instead of global stats?
-                                let stats = stats.lock().await;
-                                let mut buffer = BytesMut::with_capacity(9);
-
-                                // don't forget to include this received packet
-                                let (received, _) = stream.codec().get_stats();
-
-                                // total packet bytes received and sent
-                                buffer.put_u32((stats.received + received) as u32);
-                                buffer.put_u32(stats.sent as u32); // big-endian order
-
-                                // total payload bytes before and after compression
-                                let percent = if stats.before == 0 {
-                                    0.0 // or should the compression ratio be 100%?
-                                } else {
-                                    (stats.after as f32) / (stats.before as f32) * 100.0
-                                };
-                                buffer.put_u8(percent as u8);
-
-                                // should the response bytes about to be sent be counted?
-                                stream.send(StatusCode::Ok(buffer)).await?;
-                            }
-                            RequestCode::ResetStats => {
-                                let mut stats = stats.lock().await;
-                                stats.received = 0;
-                                stats.sent = 0;
-                                stats.before = 0;
-                                stats.after = 0;
-                                stream.codec_mut().reset_stats();
-                                compressor.reset_stats();
-
-                                // should the response bytes about to be sent be ignored?
-                                stream.send(StatusCode::Ok(BytesMut::new())).await?;
-                            }
-                            RequestCode::Compress(payload) => match compressor.compress(payload) {
-                                Ok(compressed) => stream.send(StatusCode::Ok(compressed)).await?,
-                                Err(error) => stream.send(error).await?,
-                            },
-                        };
-                    }
-
-                    // pass parsing errors back to encoder to be sent as status code packets
-                    Some(Err(error)) => stream.send(error).await?,
-                    // stream has closed, exit loop
-                    None => break,
-                }
+        tokio::select! {
+            result = &mut accepting => {
+                result?;
+                break;
+            }
+            _ = hangup.recv() => reload_config(&args, &config_rx, &config_tx, server.limiter()),
+            _ = terminate.recv() => {
+                log::info!("received SIGTERM, shutting down");
+                break;
             }
+            _ = interrupt.recv() => {
+                log::info!("received SIGINT, shutting down");
+                break;
+            }
+        }
+    }
+    notify_systemd(notify_socket.as_deref(), "STOPPING=1");
+    drop(accepting);
+    drop(shutdown);
+
+    // let open connections finish their current request
+    let shutdown_timeout = config_rx.borrow().shutdown_timeout;
+    let deadline = Duration::from_secs(shutdown_timeout);
+    if !trigger.shutdown(deadline).await {
+        log::warn!(
+            "closing {} connections still open after {}s",
+            stats.lock().await.connections,
+            shutdown_timeout
+        );
+    }
+
+    // every connection has closed or been dropped, so these are the final stats. They go
+    // to the stats file from startup, like the periodic saves, since it needs a restart
+    if let Some(path) = &config.stats_file {
+        persist::save_stats(path, &stats).await;
+    }
 
-            Ok::<(), io::Error>(()) // <- https://bit.ly/2SHCI4a
-        });
+    let stats = stats.lock().await;
+    log::info!(
+        "final stats: received {} bytes, sent {} bytes, compression ratio {}%",
+        stats.received,
+        stats.sent,
+        stats.ratio()
+    );
+
+    Ok(())
+}
+
+/// Tells systemd about a change of state on its notify socket, when it set NOTIFY_SOCKET.
+fn notify_systemd(socket: Option<&str>, state: &str) {
+    let socket = match socket {
+        Some(socket) => socket,
+        None => return,
+    };
+    if let Err(error) = systemd::notify(socket, state) {
+        log::warn!(
+            state = state,
+            error = error.to_string().as_str();
+            "systemd notification failed"
+        );
     }
 }
+
+/// Reads the config file again and applies it to new connections. Open connections
+/// pick up new limits before their next request, but keep their authentication keys.
+/// An invalid config is logged and the previous config is kept.
+fn reload_config(
+    args: &Args,
+    current: &watch::Receiver<Arc<Config>>,
+    config: &watch::Sender<Arc<Config>>,
+    limiter: &RateLimiter,
+) {
+    let new = match Config::load(args) {
+        Ok(new) => new,
+        Err(error) => {
+            log::error!(
+                error = error.to_string().as_str();
+                "invalid config, keeping the previous config"
+            );
+            return;
+        }
+    };
+
+    for option in current.borrow().restart_required(&new) {
+        log::warn!(option = option; "config option changed, but needs a restart to take effect");
+    }
+
+    log::set_max_level(new.log_level);
+    limiter.set_rates(new.rate_limit_requests, new.rate_limit_bytes);
+    log::info!(
+        "reloaded config, max payload {} bytes, packet timeout {}s, idle timeout {}s",
+        new.max_payload,
+        new.packet_timeout(),
+        new.idle_timeout
+    );
+    let _ = config.broadcast(Arc::new(new));
+}
@@ -1,15 +1,47 @@
 use bytes::BytesMut;
 use std::{error, fmt, io};
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
 pub enum RequestCode {
     Ping,
     GetStats,
     ResetStats,
     Compress(BytesMut),
+    GetHistograms,
+    Authenticate(BytesMut), // empty to ask for a challenge, or the signed challenge
+}
+
+impl RequestCode {
+    /// Returns the request code value received in the request packet header.
+    pub fn value(&self) -> u16 {
+        match self {
+            RequestCode::Ping => 1,
+            RequestCode::GetStats => 2,
+            RequestCode::ResetStats => 3,
+            RequestCode::Compress(_) => 4,
+            RequestCode::GetHistograms => 5,
+            RequestCode::Authenticate(_) => 6,
+        }
+    }
+
+    /// Returns a readable name for a request code value. Value 0 is used for
+    /// packets that could not be parsed into a request.
+    pub fn name(value: u16) -> &'static str {
+        match value {
+            1 => "ping",
+            2 => "get_stats",
+            3 => "reset_stats",
+            4 => "compress",
+            5 => "get_histograms",
+            6 => "authenticate",
+            _ => "invalid",
+        }
+    }
 }
 
 #[derive(Debug, PartialEq)]
+#[non_exhaustive]
 pub enum StatusCode {
     Ok(BytesMut), // BytesMut may be empty
     #[allow(dead_code)]
@@ -21,9 +53,62 @@ pub enum StatusCode {
     NonAscii,
     NonAlphabetic,
     NonLowerCase,
+    ServerBusy,
+    Timeout,
+    RateLimited(u32), // milliseconds until the client may retry
+    AuthenticationFailed,
+    PermissionDenied,
     IoError(io::ErrorKind),
 }
 
+impl StatusCode {
+    /// Returns the status code value sent in the response packet header.
+    pub fn value(&self) -> u16 {
+        match self {
+            // defined status codes from 0 to 3
+            StatusCode::Ok(_) => 0,
+            StatusCode::UnknownError => 1,
+            StatusCode::MessageTooLarge => 2,
+            StatusCode::UnsupportedRequestType => 3,
+            // reserved status codes from 4 to 32
+            // implementation specific status codes start at 33
+            StatusCode::EmptyBuffer => 33,
+            StatusCode::NonEmptyBuffer => 34,
+            StatusCode::NonAscii => 35,
+            StatusCode::NonAlphabetic => 36,
+            StatusCode::NonLowerCase => 37,
+            StatusCode::ServerBusy => 38,
+            StatusCode::Timeout => 39,
+            StatusCode::RateLimited(_) => 40,
+            StatusCode::AuthenticationFailed => 41,
+            StatusCode::PermissionDenied => 42,
+            // we'll pass back IO errors as an unknown error status code
+            StatusCode::IoError(_) => 1,
+        }
+    }
+
+    /// Returns a readable name for a status code value.
+    pub fn name(value: u16) -> &'static str {
+        match value {
+            0 => "ok",
+            1 => "unknown_error",
+            2 => "message_too_large",
+            3 => "unsupported_request_type",
+            33 => "empty_buffer",
+            34 => "non_empty_buffer",
+            35 => "non_ascii",
+            36 => "non_alphabetic",
+            37 => "non_lowercase",
+            38 => "server_busy",
+            39 => "timeout",
+            40 => "rate_limited",
+            41 => "authentication_failed",
+            42 => "permission_denied",
+            _ => "reserved",
+        }
+    }
+}
+
 impl fmt::Display for StatusCode {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "StatusCode: {:?}", self)
@@ -1,38 +1,67 @@
 use super::message::{RequestCode, StatusCode};
 
 use bytes::{Buf, BufMut, BytesMut};
+use std::io;
+use std::ops::RangeInclusive;
+use std::time::Instant;
 use tokio_util::codec::{Decoder, Encoder};
 
+/// Decodes request packets and encodes response packets, counting the bytes of each.
 pub struct PacketCodec {
     received: usize,
     sent: usize,
     max_payload_len: usize,
     state: DecodeState,
+    started: Option<Instant>, // when the first byte of the current packet was received
 }
 
 impl PacketCodec {
     const MAGIC_HEADER: &'static str = "STRY"; // 0x53545259
 
-    pub fn new_with_max_payload(max_payload: usize) -> PacketCodec {
-        // Note: if max_payload was a run time user provided value instead of a
-        // compile time constant, we should return a Result instead of panicking
-        assert!(
-            max_payload >= (1 << 12),
-            "max payload less than 4 KiB limit"
-        );
-        assert!(
-            max_payload < (1 << 15),
-            "max payload greater or equal to 32 KiB limit"
-        );
+    /// Max payload lengths a codec can be created with, from 4 KiB up to just under 32 KiB.
+    pub const MAX_PAYLOAD: RangeInclusive<usize> = (1 << 12)..=(1 << 15) - 1;
 
-        PacketCodec {
+    /// Creates a codec that answers requests with longer payloads with MessageTooLarge.
+    /// Returns an InvalidInput error when max_payload is outside of MAX_PAYLOAD.
+    pub fn new_with_max_payload(max_payload: usize) -> io::Result<PacketCodec> {
+        PacketCodec::check_max_payload(max_payload)?;
+
+        Ok(PacketCodec {
             sent: 0,
             received: 0,
             max_payload_len: max_payload,
             state: DecodeState::MagicHeader,
+            started: None,
+        })
+    }
+
+    /// Changes the max payload length of packets that haven't been parsed yet. A packet
+    /// whose payload length was already accepted is still read in full. Returns an
+    /// InvalidInput error, and keeps the current max, when max_payload is outside of
+    /// MAX_PAYLOAD.
+    pub fn set_max_payload(&mut self, max_payload: usize) -> io::Result<()> {
+        PacketCodec::check_max_payload(max_payload)?;
+        self.max_payload_len = max_payload;
+        Ok(())
+    }
+
+    fn check_max_payload(max_payload: usize) -> io::Result<()> {
+        if PacketCodec::MAX_PAYLOAD.contains(&max_payload) {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "max payload {} is not from {} up to {} bytes",
+                    max_payload,
+                    PacketCodec::MAX_PAYLOAD.start(),
+                    PacketCodec::MAX_PAYLOAD.end()
+                ),
+            ))
         }
     }
 
+    /// Returns the number of bytes received and sent.
     pub fn get_stats(&self) -> (usize, usize) {
         (self.received, self.sent)
     }
@@ -41,13 +70,25 @@ impl PacketCodec {
         self.sent = 0;
         self.received = 0;
     }
+
+    /// Returns when the first byte of a partially received packet arrived, or None
+    /// if there are no received bytes waiting to be parsed into a packet.
+    pub fn packet_started(&self) -> Option<Instant> {
+        self.started
+    }
+
+    /// Gives up on a partially received packet and starts looking for a new magic header.
+    pub fn reset(&mut self) {
+        self.state = DecodeState::MagicHeader;
+        self.started = None;
+    }
 }
 
 enum DecodeState {
     MagicHeader,
     PayloadLen, // pass payload length from PayloadLen through RequestCode to Payload
     RequestCode { length: usize },
-    Payload { length: usize },
+    Payload { length: usize, request: u16 },
 }
 
 impl Decoder for PacketCodec {
@@ -55,27 +96,62 @@ impl Decoder for PacketCodec {
     type Error = StatusCode;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let result = self.decode_packet(src);
+        if result != Ok(None) || !self.packet_in_progress(src) {
+            self.started = None; // packet is done, either parsed or invalid, or only garbage
+        } else if self.started.is_none() {
+            self.started = Some(Instant::now()); // start of a new packet
+        }
+
+        result
+    }
+}
+
+impl PacketCodec {
+    /// Returns whether the bytes left in src after decoding could be the start of a packet,
+    /// rather than garbage such as a trailing newline.
+    fn packet_in_progress(&self, src: &[u8]) -> bool {
+        match self.state {
+            DecodeState::MagicHeader => {
+                let header = PacketCodec::MAGIC_HEADER.as_bytes();
+                (1..header.len().min(src.len() + 1))
+                    .any(|len| src[src.len() - len..] == header[..len])
+            }
+            _ => true,
+        }
+    }
+
+    fn decode_packet(&mut self, src: &mut BytesMut) -> Result<Option<RequestCode>, StatusCode> {
         match self.state {
             DecodeState::MagicHeader => {
                 if src.len() < PacketCodec::MAGIC_HEADER.len() {
                     return Ok(None); // keep reading
                 }
 
-                // check for magic header, don't advance src yet
-                let index = if &src[0..PacketCodec::MAGIC_HEADER.len()]
-                    == PacketCodec::MAGIC_HEADER.as_bytes()
+                // look for the magic header anywhere in src, don't advance src yet
+                let header = PacketCodec::MAGIC_HEADER.as_bytes();
+                match src
+                    .windows(header.len())
+                    .position(|window| window == header)
                 {
-                    self.state = DecodeState::PayloadLen; // move on to parsing payload length
-                    PacketCodec::MAGIC_HEADER.len()
-                } else {
-                    1
-                };
-
-                // either advance src to payload length section
-                // or advance by 1 byte and look for magic header again
-                src.advance(index);
-                self.received += index;
-                self.decode(src) // recursively keep parsing
+                    Some(offset) => {
+                        // advance src past any garbage to the payload length section
+                        let index = offset + header.len();
+                        src.advance(index);
+                        self.received += index;
+                        self.state = DecodeState::PayloadLen; // move on to parsing payload length
+                        self.decode_packet(src) // recursively keep parsing
+                    }
+                    None => {
+                        // skip the garbage in a single step instead of recursing once per
+                        // byte, which could overflow the stack, but keep the last few bytes
+                        // in case they're the start of a magic header
+                        let index = src.len() - (header.len() - 1);
+                        src.advance(index);
+                        self.received += index;
+                        Ok(None) // keep reading
+                    }
+                }
             }
             DecodeState::PayloadLen => {
                 if src.len() < 2 {
@@ -90,7 +166,7 @@ impl Decoder for PacketCodec {
                     Err(StatusCode::MessageTooLarge)
                 } else {
                     self.state = DecodeState::RequestCode { length };
-                    self.decode(src) // recursively keep parsing
+                    self.decode_packet(src) // recursively keep parsing
                 }
             }
             DecodeState::RequestCode { length } => {
@@ -130,17 +206,34 @@ impl Decoder for PacketCodec {
                             // a compress request without a payload is invalid
                             Err(StatusCode::EmptyBuffer)
                         } else {
-                            self.state = DecodeState::Payload { length };
+                            self.state = DecodeState::Payload { length, request: 4 };
+                            src.reserve(length); // allocate space for payload
+                            self.decode_packet(src) // recursively keep parsing
+                        }
+                    }
+                    5 => {
+                        if length == 0 {
+                            Ok(Some(RequestCode::GetHistograms))
+                        } else {
+                            Err(StatusCode::NonEmptyBuffer)
+                        }
+                    }
+                    6 => {
+                        if length == 0 {
+                            // an empty authenticate request asks for a challenge
+                            Ok(Some(RequestCode::Authenticate(BytesMut::new())))
+                        } else {
+                            self.state = DecodeState::Payload { length, request: 6 };
                             src.reserve(length); // allocate space for payload
-                            self.decode(src) // recursively keep parsing
+                            self.decode_packet(src) // recursively keep parsing
                         }
                     }
                     _ => Err(StatusCode::UnsupportedRequestType),
                 }
             }
-            DecodeState::Payload { length } => {
+            DecodeState::Payload { length, request } => {
                 if src.len() < length {
-                    // Note: should we have a timeout in case the full payload never arrives?
+                    // the connection loop resets us if the full payload never arrives
                     return Ok(None); // keep reading
                 }
 
@@ -151,8 +244,11 @@ impl Decoder for PacketCodec {
                 let payload = src.split_to(length);
                 self.state = DecodeState::MagicHeader; // reset for next packet
 
-                // Compress is the only RequestCode with a payload
-                Ok(Some(RequestCode::Compress(payload)))
+                // Compress and Authenticate are the only RequestCodes with a payload
+                match request {
+                    6 => Ok(Some(RequestCode::Authenticate(payload))),
+                    _ => Ok(Some(RequestCode::Compress(payload))),
+                }
             }
         }
     }
@@ -168,21 +264,15 @@ impl Encoder for PacketCodec {
         dst.put(PacketCodec::MAGIC_HEADER.as_bytes());
 
         // parse return status code
-        let (payload_len, status_code, payload) = match item {
-            // defined status codes from 0 to 3
-            StatusCode::Ok(payload) => (payload.len(), 0, Some(payload)),
-            StatusCode::UnknownError => (0, 1, None),
-            StatusCode::MessageTooLarge => (0, 2, None),
-            StatusCode::UnsupportedRequestType => (0, 3, None),
-            // reserved status codes from 4 to 32
-            // implementation specific status codes start at 33
-            StatusCode::EmptyBuffer => (0, 33, None),
-            StatusCode::NonEmptyBuffer => (0, 34, None),
-            StatusCode::NonAscii => (0, 35, None),
-            StatusCode::NonAlphabetic => (0, 36, None),
-            StatusCode::NonLowerCase => (0, 37, None),
-            // we'll pass back IO errors as an unknown error status code
-            StatusCode::IoError(_) => (0, 1, None),
+        let status_code = item.value();
+        let (payload_len, payload) = match item {
+            StatusCode::Ok(payload) => (payload.len(), Some(payload)),
+            StatusCode::RateLimited(retry_after) => {
+                let mut payload = BytesMut::with_capacity(4);
+                payload.put_u32(retry_after); // uses big-endian order
+                (payload.len(), Some(payload))
+            }
+            _ => (0, None),
         };
 
         // write payload length
@@ -203,30 +293,193 @@ impl Encoder for PacketCodec {
     }
 }
 
+/// Encodes request packets and decodes response packets, the client side mirror of
+/// PacketCodec.
+pub struct ClientCodec {
+    received: usize,
+    sent: usize,
+    state: ClientDecodeState,
+}
+
+impl Default for ClientCodec {
+    fn default() -> ClientCodec {
+        ClientCodec::new()
+    }
+}
+
+impl ClientCodec {
+    pub fn new() -> ClientCodec {
+        ClientCodec {
+            received: 0,
+            sent: 0,
+            state: ClientDecodeState::MagicHeader,
+        }
+    }
+
+    /// Returns the number of bytes received and sent.
+    pub fn get_stats(&self) -> (usize, usize) {
+        (self.received, self.sent)
+    }
+
+    /// Returns the status code for a status code value and its payload, or None for a
+    /// reserved status code value or a payload that doesn't fit the status code.
+    fn status_code(value: u16, mut payload: BytesMut) -> Option<StatusCode> {
+        let status = match value {
+            0 => return Some(StatusCode::Ok(payload)),
+            40 if payload.len() == 4 => return Some(StatusCode::RateLimited(payload.get_u32())),
+            1 => StatusCode::UnknownError,
+            2 => StatusCode::MessageTooLarge,
+            3 => StatusCode::UnsupportedRequestType,
+            33 => StatusCode::EmptyBuffer,
+            34 => StatusCode::NonEmptyBuffer,
+            35 => StatusCode::NonAscii,
+            36 => StatusCode::NonAlphabetic,
+            37 => StatusCode::NonLowerCase,
+            38 => StatusCode::ServerBusy,
+            39 => StatusCode::Timeout,
+            41 => StatusCode::AuthenticationFailed,
+            42 => StatusCode::PermissionDenied,
+            _ => return None,
+        };
+
+        // only the ok and rate limited status codes have a payload
+        if payload.is_empty() {
+            Some(status)
+        } else {
+            None
+        }
+    }
+}
+
+enum ClientDecodeState {
+    MagicHeader,
+    PayloadLen,
+    StatusCode { length: usize },
+    Payload { length: usize, status: u16 },
+}
+
+impl Decoder for ClientCodec {
+    type Item = StatusCode;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        loop {
+            match self.state {
+                ClientDecodeState::MagicHeader => {
+                    let header = PacketCodec::MAGIC_HEADER.as_bytes();
+                    if src.len() < header.len() {
+                        return Ok(None); // keep reading
+                    }
+
+                    // the server only sends whole packets, so anything else is a bug
+                    if &src[..header.len()] != header {
+                        return Err(invalid_data("missing magic header"));
+                    }
+
+                    src.advance(header.len());
+                    self.received += header.len();
+                    self.state = ClientDecodeState::PayloadLen;
+                }
+                ClientDecodeState::PayloadLen => {
+                    if src.len() < 2 {
+                        return Ok(None); // keep reading
+                    }
+
+                    self.received += 2;
+                    let length = src.get_u16() as usize; // uses big-endian order
+                    self.state = ClientDecodeState::StatusCode { length };
+                }
+                ClientDecodeState::StatusCode { length } => {
+                    if src.len() < 2 {
+                        return Ok(None); // keep reading
+                    }
+
+                    self.received += 2;
+                    let status = src.get_u16(); // uses big-endian order
+                    self.state = ClientDecodeState::Payload { length, status };
+                    src.reserve(length); // allocate space for payload
+                }
+                ClientDecodeState::Payload { length, status } => {
+                    if src.len() < length {
+                        return Ok(None); // keep reading
+                    }
+
+                    self.received += length;
+                    let payload = src.split_to(length);
+                    self.state = ClientDecodeState::MagicHeader; // reset for next packet
+
+                    return ClientCodec::status_code(status, payload)
+                        .map(Some)
+                        .ok_or_else(|| invalid_data("invalid status code"));
+                }
+            }
+        }
+    }
+}
+
+impl Encoder for ClientCodec {
+    type Item = RequestCode;
+    type Error = std::io::Error;
+
+    fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let request_code = item.value();
+        let payload = match item {
+            RequestCode::Compress(payload) | RequestCode::Authenticate(payload) => payload,
+            _ => BytesMut::new(),
+        };
+        if payload.len() > u16::MAX as usize {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "payload longer than 65535 bytes",
+            ));
+        }
+
+        // write magic header, payload length, request code, then payload
+        let length = PacketCodec::MAGIC_HEADER.len() + 4 + payload.len();
+        dst.reserve(length);
+        dst.put(PacketCodec::MAGIC_HEADER.as_bytes());
+        dst.put_u16(payload.len() as u16); // uses big-endian order
+        dst.put_u16(request_code); // uses big-endian order
+        dst.put(payload);
+
+        self.sent += length; // update stats
+
+        Ok(())
+    }
+}
+
+fn invalid_data(reason: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, reason)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
 
     #[test]
-    #[should_panic]
     fn max_payload_len_too_small() {
-        let _ = PacketCodec::new_with_max_payload(4 * 1024 - 1);
+        let error = PacketCodec::new_with_max_payload(4 * 1024 - 1)
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
     }
 
     #[test]
-    #[should_panic]
     fn max_payload_len_too_large() {
-        let _ = PacketCodec::new_with_max_payload(32 * 1024);
+        let error = PacketCodec::new_with_max_payload(32 * 1024).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
     }
 
     #[test]
     fn max_payload_len_just_right() {
-        let _ = PacketCodec::new_with_max_payload(16 * 1024);
+        assert!(PacketCodec::new_with_max_payload(4 * 1024).is_ok());
+        assert!(PacketCodec::new_with_max_payload(32 * 1024 - 1).is_ok());
     }
 
     #[test]
     fn bad_request() {
-        let mut codec = PacketCodec::new_with_max_payload(16 * 1024);
+        let mut codec = PacketCodec::new_with_max_payload(16 * 1024).unwrap();
         assert_eq!(
             codec.decode(&mut BytesMut::from(&b"STRY\0\0\0\0"[..])),
             Err(StatusCode::UnsupportedRequestType)
@@ -235,7 +488,7 @@ mod tests {
 
     #[test]
     fn good_ping() {
-        let mut codec = PacketCodec::new_with_max_payload(16 * 1024);
+        let mut codec = PacketCodec::new_with_max_payload(16 * 1024).unwrap();
         assert_eq!(
             codec.decode(&mut BytesMut::from(&b"STRY\0\0\0\x01"[..])),
             Ok(Some(RequestCode::Ping))
@@ -244,7 +497,7 @@ mod tests {
 
     #[test]
     fn bad_ping() {
-        let mut codec = PacketCodec::new_with_max_payload(16 * 1024);
+        let mut codec = PacketCodec::new_with_max_payload(16 * 1024).unwrap();
         assert_eq!(
             codec.decode(&mut BytesMut::from(&b"STRY\0\x01\0\x01"[..])),
             Err(StatusCode::NonEmptyBuffer)
@@ -253,7 +506,7 @@ mod tests {
 
     #[test]
     fn good_get_stats() {
-        let mut codec = PacketCodec::new_with_max_payload(16 * 1024);
+        let mut codec = PacketCodec::new_with_max_payload(16 * 1024).unwrap();
         assert_eq!(
             codec.decode(&mut BytesMut::from(&b"STRY\0\0\0\x02"[..])),
             Ok(Some(RequestCode::GetStats))
@@ -262,7 +515,7 @@ mod tests {
 
     #[test]
     fn bad_get_stats() {
-        let mut codec = PacketCodec::new_with_max_payload(16 * 1024);
+        let mut codec = PacketCodec::new_with_max_payload(16 * 1024).unwrap();
         assert_eq!(
             codec.decode(&mut BytesMut::from(&b"STRY\0\x01\0\x02"[..])),
             Err(StatusCode::NonEmptyBuffer)
@@ -271,7 +524,7 @@ mod tests {
 
     #[test]
     fn good_reset_stats() {
-        let mut codec = PacketCodec::new_with_max_payload(16 * 1024);
+        let mut codec = PacketCodec::new_with_max_payload(16 * 1024).unwrap();
         assert_eq!(
             codec.decode(&mut BytesMut::from(&b"STRY\0\0\0\x03"[..])),
             Ok(Some(RequestCode::ResetStats))
@@ -280,7 +533,7 @@ mod tests {
 
     #[test]
     fn bad_reset_stats() {
-        let mut codec = PacketCodec::new_with_max_payload(16 * 1024);
+        let mut codec = PacketCodec::new_with_max_payload(16 * 1024).unwrap();
         assert_eq!(
             codec.decode(&mut BytesMut::from(&b"STRY\0\x01\0\x03"[..])),
             Err(StatusCode::NonEmptyBuffer)
@@ -289,7 +542,7 @@ mod tests {
 
     #[test]
     fn good_compress() {
-        let mut codec = PacketCodec::new_with_max_payload(16 * 1024);
+        let mut codec = PacketCodec::new_with_max_payload(16 * 1024).unwrap();
         assert_eq!(
             codec.decode(&mut BytesMut::from(&b"STRY\0\x05\0\x04hello"[..])),
             Ok(Some(RequestCode::Compress(BytesMut::from(&b"hello"[..]))))
@@ -298,16 +551,136 @@ mod tests {
 
     #[test]
     fn bad_compress() {
-        let mut codec = PacketCodec::new_with_max_payload(16 * 1024);
+        let mut codec = PacketCodec::new_with_max_payload(16 * 1024).unwrap();
         assert_eq!(
             codec.decode(&mut BytesMut::from(&b"STRY\0\0\0\x04"[..])),
             Err(StatusCode::EmptyBuffer)
         );
     }
 
+    #[test]
+    fn good_get_histograms() {
+        let mut codec = PacketCodec::new_with_max_payload(16 * 1024).unwrap();
+        assert_eq!(
+            codec.decode(&mut BytesMut::from(&b"STRY\0\0\0\x05"[..])),
+            Ok(Some(RequestCode::GetHistograms))
+        );
+    }
+
+    #[test]
+    fn bad_get_histograms() {
+        let mut codec = PacketCodec::new_with_max_payload(16 * 1024).unwrap();
+        assert_eq!(
+            codec.decode(&mut BytesMut::from(&b"STRY\0\x01\0\x05"[..])),
+            Err(StatusCode::NonEmptyBuffer)
+        );
+    }
+
+    #[test]
+    fn authenticate_challenge() {
+        let mut codec = PacketCodec::new_with_max_payload(16 * 1024).unwrap();
+        assert_eq!(
+            codec.decode(&mut BytesMut::from(&b"STRY\0\0\0\x06"[..])),
+            Ok(Some(RequestCode::Authenticate(BytesMut::new())))
+        );
+    }
+
+    #[test]
+    fn authenticate_signature() {
+        let mut codec = PacketCodec::new_with_max_payload(16 * 1024).unwrap();
+        assert_eq!(
+            codec.decode(&mut BytesMut::from(
+                &b"STRY\0\x04\0\x06\x01\x02\x03\x04"[..]
+            )),
+            Ok(Some(RequestCode::Authenticate(BytesMut::from(
+                &b"\x01\x02\x03\x04"[..]
+            ))))
+        );
+    }
+
+    #[test]
+    fn long_garbage() {
+        let mut codec = PacketCodec::new_with_max_payload(16 * 1024).unwrap();
+        let mut buffer = BytesMut::from(&[b'a'; 1 << 20][..]);
+        assert_eq!(codec.decode(&mut buffer), Ok(None));
+        assert_eq!(buffer.len(), 3);
+
+        buffer.extend_from_slice(b"STRY\0\0\0\x01");
+        assert_eq!(codec.decode(&mut buffer), Ok(Some(RequestCode::Ping)));
+        assert_eq!(codec.get_stats(), ((1 << 20) + 8, 0));
+    }
+
+    #[test]
+    fn set_max_payload() {
+        let mut codec = PacketCodec::new_with_max_payload(16 * 1024).unwrap();
+        let mut buffer = BytesMut::from(&b"STRY\x20\0\0\x04"[..]);
+        codec.set_max_payload(4 * 1024).unwrap();
+        assert_eq!(codec.decode(&mut buffer), Err(StatusCode::MessageTooLarge));
+    }
+
+    #[test]
+    fn set_max_payload_too_large() {
+        let mut codec = PacketCodec::new_with_max_payload(16 * 1024).unwrap();
+        assert!(codec.set_max_payload(32 * 1024).is_err());
+
+        // the previous max is kept
+        let mut buffer = BytesMut::from(&b"STRY\x40\x01\0\x04"[..]);
+        assert_eq!(codec.decode(&mut buffer), Err(StatusCode::MessageTooLarge));
+    }
+
+    #[test]
+    fn partial_packet_started() {
+        let mut codec = PacketCodec::new_with_max_payload(16 * 1024).unwrap();
+        let mut buffer = BytesMut::from(&b"STRY\0\x05\0\x04he"[..]);
+        assert_eq!(codec.decode(&mut buffer), Ok(None));
+        let started = codec.packet_started().expect("partial packet not started");
+
+        buffer.extend_from_slice(b"llo");
+        assert_eq!(
+            codec.decode(&mut buffer),
+            Ok(Some(RequestCode::Compress(BytesMut::from(&b"hello"[..]))))
+        );
+        assert_eq!(codec.packet_started(), None);
+        assert!(started.elapsed().as_secs() < 1);
+    }
+
+    #[test]
+    fn garbage_not_started() {
+        let mut codec = PacketCodec::new_with_max_payload(16 * 1024).unwrap();
+        let mut buffer = BytesMut::from(&b"STRY\0\0\0\x01\n"[..]);
+        assert_eq!(codec.decode(&mut buffer), Ok(Some(RequestCode::Ping)));
+        assert_eq!(codec.decode(&mut buffer), Ok(None));
+        assert_eq!(codec.packet_started(), None);
+
+        buffer.extend_from_slice(b"garbage");
+        assert_eq!(codec.decode(&mut buffer), Ok(None));
+        assert_eq!(codec.packet_started(), None);
+
+        // the last bytes could start a magic header, until they can't
+        buffer.extend_from_slice(b"ST");
+        assert_eq!(codec.decode(&mut buffer), Ok(None));
+        assert!(codec.packet_started().is_some());
+        buffer.extend_from_slice(b"x");
+        assert_eq!(codec.decode(&mut buffer), Ok(None));
+        assert_eq!(codec.packet_started(), None);
+    }
+
+    #[test]
+    fn reset_partial_packet() {
+        let mut codec = PacketCodec::new_with_max_payload(16 * 1024).unwrap();
+        let mut buffer = BytesMut::from(&b"STRY\0\x05\0\x04he"[..]);
+        assert_eq!(codec.decode(&mut buffer), Ok(None));
+
+        // the stale payload bytes are skipped while looking for a magic header
+        codec.reset();
+        assert_eq!(codec.packet_started(), None);
+        buffer.extend_from_slice(b"STRY\0\0\0\x01");
+        assert_eq!(codec.decode(&mut buffer), Ok(Some(RequestCode::Ping)));
+    }
+
     #[test]
     fn ok_with_payload() {
-        let mut codec = PacketCodec::new_with_max_payload(16 * 1024);
+        let mut codec = PacketCodec::new_with_max_payload(16 * 1024).unwrap();
         let mut buffer = BytesMut::new();
         codec
             .encode(StatusCode::Ok(BytesMut::from(&b"hello"[..])), &mut buffer)
@@ -317,7 +690,7 @@ mod tests {
 
     #[test]
     fn ok_without_payload() {
-        let mut codec = PacketCodec::new_with_max_payload(16 * 1024);
+        let mut codec = PacketCodec::new_with_max_payload(16 * 1024).unwrap();
         let mut buffer = BytesMut::new();
         codec
             .encode(StatusCode::Ok(BytesMut::new()), &mut buffer)
@@ -327,7 +700,7 @@ mod tests {
 
     #[test]
     fn unknown_error() {
-        let mut codec = PacketCodec::new_with_max_payload(16 * 1024);
+        let mut codec = PacketCodec::new_with_max_payload(16 * 1024).unwrap();
         let mut buffer = BytesMut::new();
         codec.encode(StatusCode::UnknownError, &mut buffer).unwrap();
         assert_eq!(buffer, &b"STRY\0\0\0\x01"[..]);
@@ -335,7 +708,7 @@ mod tests {
 
     #[test]
     fn message_to_large() {
-        let mut codec = PacketCodec::new_with_max_payload(16 * 1024);
+        let mut codec = PacketCodec::new_with_max_payload(16 * 1024).unwrap();
         let mut buffer = BytesMut::new();
         codec
             .encode(StatusCode::MessageTooLarge, &mut buffer)
@@ -345,7 +718,7 @@ mod tests {
 
     #[test]
     fn unsupported_request() {
-        let mut codec = PacketCodec::new_with_max_payload(16 * 1024);
+        let mut codec = PacketCodec::new_with_max_payload(16 * 1024).unwrap();
         let mut buffer = BytesMut::new();
         codec
             .encode(StatusCode::UnsupportedRequestType, &mut buffer)
@@ -355,7 +728,7 @@ mod tests {
 
     #[test]
     fn empty_buffer() {
-        let mut codec = PacketCodec::new_with_max_payload(16 * 1024);
+        let mut codec = PacketCodec::new_with_max_payload(16 * 1024).unwrap();
         let mut buffer = BytesMut::new();
         codec.encode(StatusCode::EmptyBuffer, &mut buffer).unwrap();
         assert_eq!(buffer, &b"STRY\0\0\0\x21"[..]);
@@ -363,7 +736,7 @@ mod tests {
 
     #[test]
     fn non_empty_buffer() {
-        let mut codec = PacketCodec::new_with_max_payload(16 * 1024);
+        let mut codec = PacketCodec::new_with_max_payload(16 * 1024).unwrap();
         let mut buffer = BytesMut::new();
         codec
             .encode(StatusCode::NonEmptyBuffer, &mut buffer)
@@ -373,7 +746,7 @@ mod tests {
 
     #[test]
     fn non_ascii() {
-        let mut codec = PacketCodec::new_with_max_payload(16 * 1024);
+        let mut codec = PacketCodec::new_with_max_payload(16 * 1024).unwrap();
         let mut buffer = BytesMut::new();
         codec.encode(StatusCode::NonAscii, &mut buffer).unwrap();
         assert_eq!(buffer, &b"STRY\0\0\0\x23"[..]);
@@ -381,7 +754,7 @@ mod tests {
 
     #[test]
     fn non_alphabetic() {
-        let mut codec = PacketCodec::new_with_max_payload(16 * 1024);
+        let mut codec = PacketCodec::new_with_max_payload(16 * 1024).unwrap();
         let mut buffer = BytesMut::new();
         codec
             .encode(StatusCode::NonAlphabetic, &mut buffer)
@@ -391,19 +764,220 @@ mod tests {
 
     #[test]
     fn non_lowercase() {
-        let mut codec = PacketCodec::new_with_max_payload(16 * 1024);
+        let mut codec = PacketCodec::new_with_max_payload(16 * 1024).unwrap();
         let mut buffer = BytesMut::new();
         codec.encode(StatusCode::NonLowerCase, &mut buffer).unwrap();
         assert_eq!(buffer, &b"STRY\0\0\0\x25"[..]);
     }
 
+    #[test]
+    fn server_busy() {
+        let mut codec = PacketCodec::new_with_max_payload(16 * 1024).unwrap();
+        let mut buffer = BytesMut::new();
+        codec.encode(StatusCode::ServerBusy, &mut buffer).unwrap();
+        assert_eq!(buffer, &b"STRY\0\0\0\x26"[..]);
+    }
+
+    #[test]
+    fn timeout() {
+        let mut codec = PacketCodec::new_with_max_payload(16 * 1024).unwrap();
+        let mut buffer = BytesMut::new();
+        codec.encode(StatusCode::Timeout, &mut buffer).unwrap();
+        assert_eq!(buffer, &b"STRY\0\0\0\x27"[..]);
+    }
+
+    #[test]
+    fn rate_limited() {
+        let mut codec = PacketCodec::new_with_max_payload(16 * 1024).unwrap();
+        let mut buffer = BytesMut::new();
+        codec
+            .encode(StatusCode::RateLimited(1500), &mut buffer)
+            .unwrap();
+        assert_eq!(buffer, &b"STRY\0\x04\0\x28\0\0\x05\xdc"[..]);
+    }
+
+    #[test]
+    fn authentication_failed() {
+        let mut codec = PacketCodec::new_with_max_payload(16 * 1024).unwrap();
+        let mut buffer = BytesMut::new();
+        codec
+            .encode(StatusCode::AuthenticationFailed, &mut buffer)
+            .unwrap();
+        assert_eq!(buffer, &b"STRY\0\0\0\x29"[..]);
+    }
+
+    #[test]
+    fn permission_denied() {
+        let mut codec = PacketCodec::new_with_max_payload(16 * 1024).unwrap();
+        let mut buffer = BytesMut::new();
+        codec
+            .encode(StatusCode::PermissionDenied, &mut buffer)
+            .unwrap();
+        assert_eq!(buffer, &b"STRY\0\0\0\x2a"[..]);
+    }
+
     #[test]
     fn io_error() {
-        let mut codec = PacketCodec::new_with_max_payload(16 * 1024);
+        let mut codec = PacketCodec::new_with_max_payload(16 * 1024).unwrap();
         let mut buffer = BytesMut::new();
         codec
             .encode(StatusCode::IoError(std::io::ErrorKind::Other), &mut buffer)
             .unwrap();
         assert_eq!(buffer, &b"STRY\0\0\0\x01"[..]); // unknown error
     }
+
+    #[test]
+    fn client_request() {
+        let mut client = ClientCodec::new();
+        let mut buffer = BytesMut::new();
+        client.encode(RequestCode::Ping, &mut buffer).unwrap();
+        client
+            .encode(RequestCode::Compress(BytesMut::from("aaab")), &mut buffer)
+            .unwrap();
+        assert_eq!(buffer, &b"STRY\0\0\0\x01STRY\0\x04\0\x04aaab"[..]);
+        assert_eq!(client.get_stats(), (0, 20));
+
+        // the server decodes what the client encodes
+        let mut server = PacketCodec::new_with_max_payload(16 * 1024).unwrap();
+        assert_eq!(server.decode(&mut buffer), Ok(Some(RequestCode::Ping)));
+        assert_eq!(
+            server.decode(&mut buffer),
+            Ok(Some(RequestCode::Compress(BytesMut::from("aaab"))))
+        );
+    }
+
+    #[test]
+    fn client_response() {
+        let mut server = PacketCodec::new_with_max_payload(16 * 1024).unwrap();
+        let mut buffer = BytesMut::new();
+        server
+            .encode(StatusCode::Ok(BytesMut::from("3ab")), &mut buffer)
+            .unwrap();
+        server
+            .encode(StatusCode::RateLimited(1500), &mut buffer)
+            .unwrap();
+        server.encode(StatusCode::NonAscii, &mut buffer).unwrap();
+
+        // responses may arrive a byte at a time
+        let mut client = ClientCodec::new();
+        let mut received = BytesMut::new();
+        let mut responses = Vec::new();
+        for byte in buffer.iter() {
+            received.put_u8(*byte);
+            if let Some(status) = client.decode(&mut received).unwrap() {
+                responses.push(status);
+            }
+        }
+        assert_eq!(
+            responses,
+            vec![
+                StatusCode::Ok(BytesMut::from("3ab")),
+                StatusCode::RateLimited(1500),
+                StatusCode::NonAscii
+            ]
+        );
+        assert_eq!(client.get_stats(), (buffer.len(), 0));
+    }
+
+    #[test]
+    fn client_invalid_response() {
+        let mut client = ClientCodec::new();
+        let error = client
+            .decode(&mut BytesMut::from(&b"STRY\0\0\0\x04"[..]))
+            .unwrap_err(); // reserved status code
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+        let mut client = ClientCodec::new();
+        assert!(client
+            .decode(&mut BytesMut::from(&b"STRY\0\x01\0\x21a"[..]))
+            .is_err()); // empty buffer status with a payload
+        assert!(ClientCodec::new()
+            .decode(&mut BytesMut::from(&b"xSTRY\0\0\0\0"[..]))
+            .is_err());
+    }
+
+    fn request() -> impl Strategy<Value = RequestCode> {
+        let payload = |min| prop::collection::vec(any::<u8>(), min..100);
+        prop_oneof![
+            Just(RequestCode::Ping),
+            Just(RequestCode::GetStats),
+            Just(RequestCode::ResetStats),
+            Just(RequestCode::GetHistograms),
+            payload(1).prop_map(|payload| RequestCode::Compress(BytesMut::from(&payload[..]))),
+            payload(0).prop_map(|payload| RequestCode::Authenticate(BytesMut::from(&payload[..]))),
+        ]
+    }
+
+    /// Bytes that don't contain a magic header, so they can't hide a packet.
+    fn garbage() -> impl Strategy<Value = Vec<u8>> {
+        prop::collection::vec(any::<u8>(), 0..20)
+            .prop_filter("contains a magic header", |garbage| {
+                !garbage.windows(4).any(|window| window == b"STRY")
+            })
+    }
+
+    /// Feeds bytes to a codec in chunks of the lengths, cycling through them, and
+    /// returns everything it decoded.
+    fn decode_split(
+        codec: &mut PacketCodec,
+        mut bytes: &[u8],
+        lengths: &[usize],
+    ) -> Vec<Result<RequestCode, StatusCode>> {
+        let mut results = Vec::new();
+        let mut buffer = BytesMut::new();
+        for length in lengths.iter().cycle() {
+            if bytes.is_empty() {
+                break;
+            }
+            let (chunk, rest) = bytes.split_at((*length).min(bytes.len()));
+            buffer.extend_from_slice(chunk);
+            bytes = rest;
+
+            loop {
+                match codec.decode(&mut buffer) {
+                    Ok(Some(request)) => results.push(Ok(request)),
+                    Ok(None) => break,
+                    Err(status) => results.push(Err(status)), // the server keeps reading
+                }
+            }
+        }
+        results
+    }
+
+    proptest! {
+        #[test]
+        fn fragmented_requests(
+            packets in prop::collection::vec((garbage(), request()), 1..20),
+            lengths in prop::collection::vec(1usize..64, 1..10),
+        ) {
+            let mut bytes = BytesMut::new();
+            let mut client = ClientCodec::new();
+            for (garbage, request) in &packets {
+                bytes.extend_from_slice(garbage);
+                client.encode(request.clone(), &mut bytes).unwrap();
+            }
+
+            let mut codec = PacketCodec::new_with_max_payload(4096).unwrap();
+            let requests: Vec<_> = packets.into_iter().map(|(_, request)| Ok(request)).collect();
+            prop_assert_eq!(decode_split(&mut codec, &bytes, &lengths), requests);
+            prop_assert_eq!(codec.get_stats(), (bytes.len(), 0)); // garbage is received too
+        }
+
+        #[test]
+        fn fragmented_garbage(
+            bytes in prop::collection::vec(any::<u8>(), 0..200),
+            lengths in prop::collection::vec(1usize..64, 1..10),
+        ) {
+            // a valid header with random bytes before and after it
+            let mut bytes = bytes;
+            let middle = bytes.len() / 2;
+            bytes.splice(middle..middle, b"STRY\0\x02\0\x04".iter().copied());
+
+            let mut whole = PacketCodec::new_with_max_payload(4096).unwrap();
+            let expected = decode_split(&mut whole, &bytes, &[bytes.len().max(1)]);
+            let mut split = PacketCodec::new_with_max_payload(4096).unwrap();
+            prop_assert_eq!(decode_split(&mut split, &bytes, &lengths), expected);
+            prop_assert_eq!(split.get_stats(), whole.get_stats());
+        }
+    }
 }
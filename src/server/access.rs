@@ -0,0 +1,82 @@
+use ipnet::IpNet;
+use std::net::IpAddr;
+
+/// CIDR allow and deny rules for client addresses.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct AccessList<'a> {
+    allow: &'a [IpNet], // empty to allow every address that isn't denied
+    deny: &'a [IpNet],
+}
+
+impl<'a> AccessList<'a> {
+    pub fn new(allow: &'a [IpNet], deny: &'a [IpNet]) -> AccessList<'a> {
+        AccessList { allow, deny }
+    }
+
+    /// Deny rules take precedence over allow rules. An address that matches no rule
+    /// is only permitted when there are no allow rules.
+    pub fn permits(&self, addr: IpAddr) -> bool {
+        // ipv4 clients of a dual stack listener have ipv4 mapped ipv6 addresses
+        let addr = addr.to_canonical();
+
+        if self.deny.iter().any(|net| net.contains(&addr)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nets(nets: &[&str]) -> Vec<IpNet> {
+        nets.iter().map(|net| net.parse().unwrap()).collect()
+    }
+
+    fn addr(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn empty() {
+        let access = AccessList::default();
+        assert!(access.permits(addr("192.168.0.1")));
+        assert!(access.permits(addr("::1")));
+    }
+
+    #[test]
+    fn allow() {
+        let allow = nets(&["10.0.0.0/8", "fd00::/8"]);
+        let access = AccessList::new(&allow, &[]);
+        assert!(access.permits(addr("10.1.2.3")));
+        assert!(access.permits(addr("fd12::1")));
+        assert!(!access.permits(addr("192.168.0.1")));
+        assert!(!access.permits(addr("::1")));
+    }
+
+    #[test]
+    fn deny() {
+        let deny = nets(&["192.168.0.0/16", "::1/128"]);
+        let access = AccessList::new(&[], &deny);
+        assert!(!access.permits(addr("192.168.10.1")));
+        assert!(!access.permits(addr("::1")));
+        assert!(access.permits(addr("10.1.2.3")));
+    }
+
+    #[test]
+    fn deny_before_allow() {
+        let (allow, deny) = (nets(&["10.0.0.0/8"]), nets(&["10.0.0.0/24"]));
+        let access = AccessList::new(&allow, &deny);
+        assert!(!access.permits(addr("10.0.0.1")));
+        assert!(access.permits(addr("10.0.1.1")));
+    }
+
+    #[test]
+    fn ipv4_mapped() {
+        let allow = nets(&["127.0.0.0/8"]);
+        let access = AccessList::new(&allow, &[]);
+        assert!(access.permits(addr("::ffff:127.0.0.1")));
+        assert!(!access.permits(addr("::ffff:10.0.0.1")));
+    }
+}
@@ -0,0 +1,126 @@
+use crate::message::RequestCode;
+
+use bytes::BytesMut;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// What a connection is allowed to do, in order of increasing privilege.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    Anonymous, // not authenticated yet
+    User,
+    Admin,
+}
+
+impl Role {
+    /// Returns the role value sent in a successful Authenticate response.
+    pub fn value(&self) -> u8 {
+        match self {
+            Role::Anonymous => 0,
+            Role::User => 1,
+            Role::Admin => 2,
+        }
+    }
+
+    /// Returns the least privileged role allowed to make a request.
+    pub fn required(request: &RequestCode) -> Role {
+        match request {
+            RequestCode::Ping | RequestCode::Authenticate(_) => Role::Anonymous,
+            RequestCode::ResetStats => Role::Admin, // wipes every client's stats
+            _ => Role::User,
+        }
+    }
+}
+
+/// Shared secret keys that clients prove they know by signing a challenge.
+pub struct Keys {
+    user: Option<Vec<u8>>,
+    admin: Option<Vec<u8>>,
+}
+
+impl Keys {
+    pub const CHALLENGE_LEN: usize = 32;
+
+    pub fn new(user: Option<&str>, admin: Option<&str>) -> Keys {
+        Keys {
+            user: user.map(|key| key.as_bytes().to_vec()),
+            admin: admin.map(|key| key.as_bytes().to_vec()),
+        }
+    }
+
+    /// Authentication is only required when at least one key is configured.
+    pub fn is_enabled(&self) -> bool {
+        self.user.is_some() || self.admin.is_some()
+    }
+
+    /// Returns the role of a connection before it authenticates.
+    pub fn initial_role(&self) -> Role {
+        if self.is_enabled() {
+            Role::Anonymous
+        } else {
+            Role::Admin // nothing to authenticate with, so allow everything
+        }
+    }
+
+    /// Returns a new random challenge for a client to sign.
+    pub fn challenge() -> BytesMut {
+        let mut challenge = BytesMut::new();
+        challenge.resize(Keys::CHALLENGE_LEN, 0);
+        rand::thread_rng().fill_bytes(&mut challenge);
+        challenge
+    }
+
+    /// Returns the role of the key that signed challenge, checking the admin key first.
+    pub fn verify(&self, challenge: &[u8], signature: &[u8]) -> Option<Role> {
+        let keys = [(&self.admin, Role::Admin), (&self.user, Role::User)];
+        keys.iter().find_map(|(key, role)| {
+            let mut mac = HmacSha256::new_from_slice(key.as_ref()?).ok()?;
+            mac.update(challenge);
+            mac.verify_slice(signature).ok().map(|_| *role) // constant time comparison
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::sign;
+
+    #[test]
+    fn disabled() {
+        let keys = Keys::new(None, None);
+        assert!(!keys.is_enabled());
+        assert_eq!(keys.initial_role(), Role::Admin);
+    }
+
+    #[test]
+    fn verify() {
+        let keys = Keys::new(Some("user secret"), Some("admin secret"));
+        assert_eq!(keys.initial_role(), Role::Anonymous);
+
+        let challenge = Keys::challenge();
+        assert_eq!(challenge.len(), Keys::CHALLENGE_LEN);
+        assert_ne!(challenge, Keys::challenge());
+
+        let user = sign("user secret", &challenge);
+        assert_eq!(keys.verify(&challenge, &user), Some(Role::User));
+        let admin = sign("admin secret", &challenge);
+        assert_eq!(keys.verify(&challenge, &admin), Some(Role::Admin));
+
+        let wrong = sign("wrong secret", &challenge);
+        assert_eq!(keys.verify(&challenge, &wrong), None);
+        assert_eq!(keys.verify(&challenge, &user[..16]), None);
+        assert_eq!(keys.verify(&Keys::challenge(), &user), None);
+    }
+
+    #[test]
+    fn required() {
+        assert_eq!(Role::required(&RequestCode::Ping), Role::Anonymous);
+        assert_eq!(Role::required(&RequestCode::GetStats), Role::User);
+        assert_eq!(Role::required(&RequestCode::ResetStats), Role::Admin);
+        assert!(Role::User < Role::Admin);
+    }
+}
@@ -0,0 +1,279 @@
+use super::shutdown::Shutdown;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc;
+
+/// The first line of a capture file, naming the connection it recorded.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Header {
+    pub conn: u64,
+    pub peer: String,
+    pub opened_ms: u64, // since the unix epoch
+}
+
+/// Which way recorded bytes went.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    In,  // read from the client
+    Out, // written to the client
+}
+
+/// Bytes read or written in one go, every line of a capture file after the header.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    pub time_us: u64, // since the connection was opened
+    pub direction: Direction,
+    #[serde(serialize_with = "to_hex", deserialize_with = "from_hex")]
+    pub data: Vec<u8>,
+}
+
+/// Sends the records of one connection to the task writing its capture file.
+pub struct Recorder {
+    opened: Instant,
+    records: mpsc::UnboundedSender<Record>,
+}
+
+impl Recorder {
+    /// Creates a capture file for a connection in dir, named after when it was opened
+    /// and its id, and spawns a task to write records to it until the Recorder is dropped.
+    /// The task holds shutdown until the file is flushed.
+    pub async fn create(
+        dir: &Path,
+        conn: u64,
+        peer: &str,
+        shutdown: Shutdown,
+    ) -> io::Result<Recorder> {
+        let opened_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let path = dir.join(format!("{}-{}.capture", opened_ms, conn));
+        let mut file = BufWriter::new(fs::File::create(&path).await?);
+
+        let header = Header {
+            conn,
+            peer: peer.to_string(),
+            opened_ms,
+        };
+        file.write_all(&line(&header)?).await?;
+
+        let (records, mut received) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let _shutdown = shutdown;
+            let result = async {
+                while let Some(record) = received.recv().await {
+                    file.write_all(&line(&record)?).await?;
+                }
+                file.flush().await
+            };
+            if let Err(error) = result.await {
+                log::error!(
+                    path = path.display().to_string().as_str(),
+                    error = error.to_string().as_str();
+                    "failed to write capture"
+                );
+            }
+        });
+
+        Ok(Recorder {
+            opened: Instant::now(),
+            records,
+        })
+    }
+
+    fn record(&self, direction: Direction, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let record = Record {
+            time_us: self.opened.elapsed().as_micros() as u64,
+            direction,
+            data: data.to_vec(),
+        };
+        let _ = self.records.send(record); // the writer only stops on an error
+    }
+}
+
+/// A stream that records the bytes read from and written to it, when given a Recorder.
+pub struct Capture<S> {
+    inner: S,
+    recorder: Option<Recorder>,
+}
+
+impl<S> Capture<S> {
+    pub fn new(inner: S, recorder: Option<Recorder>) -> Capture<S> {
+        Capture { inner, recorder }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Capture<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let (Poll::Ready(Ok(len)), Some(recorder)) = (&result, &self.recorder) {
+            recorder.record(Direction::In, &buf[..*len]);
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Capture<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let (Poll::Ready(Ok(len)), Some(recorder)) = (&result, &self.recorder) {
+            recorder.record(Direction::Out, &buf[..*len]);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Reads a capture file written by a Recorder.
+pub fn load(path: &Path) -> io::Result<(Header, Vec<Record>)> {
+    let mut lines = BufReader::new(File::open(path)?).lines();
+    let header = match lines.next() {
+        Some(line) => serde_json::from_str(&line?)?,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "empty capture file",
+            ))
+        }
+    };
+
+    let mut records = Vec::new();
+    for line in lines {
+        records.push(serde_json::from_str(&line?)?);
+    }
+    Ok((header, records))
+}
+
+/// Serializes value as a JSON line.
+fn line<T: Serialize>(value: &T) -> io::Result<Vec<u8>> {
+    let mut line = serde_json::to_vec(value)?;
+    line.push(b'\n');
+    Ok(line)
+}
+
+fn to_hex<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    let hex: String = data.iter().map(|byte| format!("{:02x}", byte)).collect();
+    serializer.serialize_str(&hex)
+}
+
+fn from_hex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    let hex = String::deserialize(deserializer)?;
+    if hex.len() % 2 != 0 {
+        return Err(serde::de::Error::custom("odd number of hex digits"));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|index| {
+            hex.get(index..index + 2)
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                .ok_or_else(|| serde::de::Error::custom("invalid hex digits"))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::shutdown;
+    use std::env;
+    use std::time::Duration;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::UnixStream;
+
+    #[test]
+    fn hex_record() {
+        let record = Record {
+            time_us: 1500,
+            direction: Direction::Out,
+            data: b"STRY\x00\x00\x00\xff".to_vec(),
+        };
+        let json = r#"{"time_us":1500,"direction":"out","data":"53545259000000ff"}"#;
+        assert_eq!(serde_json::to_string(&record).unwrap(), json);
+        assert_eq!(serde_json::from_str::<Record>(json).unwrap(), record);
+
+        let odd = r#"{"time_us":0,"direction":"in","data":"535"}"#;
+        assert!(serde_json::from_str::<Record>(odd).is_err());
+        let invalid = r#"{"time_us":0,"direction":"in","data":"zz"}"#;
+        assert!(serde_json::from_str::<Record>(invalid).is_err());
+    }
+
+    #[tokio::test]
+    async fn capture_stream() {
+        let dir = env::temp_dir().join(format!(
+            "compression-service-capture-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let (server, mut client) = UnixStream::pair().unwrap();
+        let (_trigger, shutdown) = shutdown::channel();
+        let recorder = Recorder::create(&dir, 7, "unix client", shutdown)
+            .await
+            .unwrap();
+        let mut server = Capture::new(server, Some(recorder));
+
+        client.write_all(b"ping").await.unwrap();
+        let mut buffer = [0; 4];
+        server.read_exact(&mut buffer).await.unwrap();
+        server.write_all(b"pong").await.unwrap();
+        drop(server); // the writer task finishes the file
+
+        client.read_exact(&mut buffer).await.unwrap();
+        let path = std::fs::read_dir(&dir)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        assert!(path.to_string_lossy().ends_with("-7.capture"));
+
+        // the writer task flushes once the recorder is dropped
+        let (header, records) = loop {
+            match load(&path) {
+                Ok((header, records)) if records.len() == 2 => break (header, records),
+                _ => tokio::time::delay_for(Duration::from_millis(10)).await,
+            }
+        };
+        assert_eq!((header.conn, header.peer.as_str()), (7, "unix client"));
+        let records: Vec<_> = records
+            .into_iter()
+            .map(|record| (record.direction, record.data))
+            .collect();
+        assert_eq!(
+            records,
+            vec![
+                (Direction::In, b"ping".to_vec()),
+                (Direction::Out, b"pong".to_vec())
+            ]
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,581 @@
+use super::listener::ListenAddr;
+use crate::packet::PacketCodec;
+
+use ipnet::IpNet;
+use log::LevelFilter;
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::{error, fmt, fs, io};
+use structopt::StructOpt;
+
+/// Command line flags. Any flag that is set overrides the same option in the config file.
+#[derive(Debug, StructOpt)]
+#[structopt(about = "Compresses lowercase ascii payloads over the STRY packet protocol")]
+pub struct Args {
+    /// TOML config file
+    #[structopt(short, long, parse(from_os_str))]
+    pub config: Option<PathBuf>,
+
+    /// Address and port, tls:address and port, or unix:path to accept clients on, may be repeated
+    #[structopt(short, long, number_of_values = 1)]
+    pub listen: Vec<ListenAddr>,
+
+    /// Only accept clients from this CIDR network, may be repeated
+    #[structopt(long, number_of_values = 1)]
+    pub allow: Vec<IpNet>,
+
+    /// Never accept clients from this CIDR network, may be repeated
+    #[structopt(long, number_of_values = 1)]
+    pub deny: Vec<IpNet>,
+
+    /// PEM certificate chain file for tls listeners
+    #[structopt(long, parse(from_os_str))]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key file for tls listeners
+    #[structopt(long, parse(from_os_str))]
+    pub tls_key: Option<PathBuf>,
+
+    /// PEM CA certificates file that tls clients must present a certificate signed by
+    #[structopt(long, parse(from_os_str))]
+    pub tls_client_ca: Option<PathBuf>,
+
+    /// Address and port to serve Prometheus metrics on
+    #[structopt(long)]
+    pub metrics: Option<SocketAddr>,
+
+    /// Max payload length in bytes, from 4096 up to 32767
+    #[structopt(long)]
+    pub max_payload: Option<usize>,
+
+    /// Max number of simultaneous clients, 0 for unlimited
+    #[structopt(long)]
+    pub max_connections: Option<usize>,
+
+    /// What to do with new clients past max connections, either queue or reject
+    #[structopt(long)]
+    pub busy_action: Option<BusyAction>,
+
+    /// Seconds to wait for the rest of a partially received packet, 0 to wait forever
+    #[structopt(long)]
+    pub packet_timeout: Option<u64>,
+
+    /// Seconds to wait for a new packet before closing a connection, 0 to wait forever
+    #[structopt(long)]
+    pub idle_timeout: Option<u64>,
+
+    /// Seconds to let open connections finish their current request after SIGTERM or SIGINT
+    #[structopt(long)]
+    pub shutdown_timeout: Option<u64>,
+
+    /// Requests per second allowed from each client address, 0 for unlimited
+    #[structopt(long)]
+    pub rate_limit_requests: Option<u32>,
+
+    /// Payload bytes per second allowed from each client address, 0 for unlimited
+    #[structopt(long)]
+    pub rate_limit_bytes: Option<u32>,
+
+    /// File to save stats to, and restore them from at startup
+    #[structopt(long, parse(from_os_str))]
+    pub stats_file: Option<PathBuf>,
+
+    /// Seconds between saves of the stats file, 0 to only save on shutdown
+    #[structopt(long)]
+    pub stats_interval: Option<u64>,
+
+    /// Directory to record the bytes sent and received by each new connection to
+    #[structopt(long, parse(from_os_str))]
+    pub capture_dir: Option<PathBuf>,
+
+    /// Capture file to replay against a fresh connection, printing the responses that
+    /// differ from the recorded ones, then exit instead of serving clients
+    #[structopt(long, parse(from_os_str))]
+    pub replay: Option<PathBuf>,
+
+    /// Compression algorithm to enable, may be repeated
+    #[structopt(long = "algorithm", number_of_values = 1)]
+    pub algorithms: Vec<Algorithm>,
+
+    /// One of off, error, warn, info, debug, or trace
+    #[structopt(long)]
+    pub log_level: Option<LevelFilter>,
+
+    /// File to write JSON lines logs to instead of stderr
+    #[structopt(long, parse(from_os_str))]
+    pub log_file: Option<PathBuf>,
+
+    /// Bytes a log file may grow to before it's rotated, 0 to never rotate
+    #[structopt(long)]
+    pub log_max_size: Option<u64>,
+
+    /// Number of rotated log files to keep
+    #[structopt(long)]
+    pub log_max_files: Option<usize>,
+}
+
+/// Compression algorithms that can be enabled for Compress requests.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Algorithm {
+    Prefix, // the prefix encoder in compress.rs
+}
+
+impl std::str::FromStr for Algorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "prefix" => Ok(Algorithm::Prefix),
+            _ => Err(format!("unknown algorithm '{}'", s)),
+        }
+    }
+}
+
+/// What to do with new clients when max connections are already open.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BusyAction {
+    Queue,  // wait to accept until a connection closes
+    Reject, // accept, send a ServerBusy status code, then close
+}
+
+impl std::str::FromStr for BusyAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "queue" => Ok(BusyAction::Queue),
+            "reject" => Ok(BusyAction::Reject),
+            _ => Err(format!("unknown busy action '{}'", s)),
+        }
+    }
+}
+
+/// Server runtime options.
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen: Vec<ListenAddr>,
+    pub allow: Vec<IpNet>, // empty to allow every client that isn't denied
+    pub deny: Vec<IpNet>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub tls_client_ca: Option<PathBuf>, // client certificates aren't required without it
+    pub metrics: Option<SocketAddr>,
+    pub max_payload: usize,
+    pub max_connections: usize, // 0 for unlimited
+    pub busy_action: BusyAction,
+    pub packet_timeout: Option<u64>, // seconds, 0 for no timeout, see packet_timeout()
+    pub idle_timeout: u64,           // seconds, 0 for no timeout
+    pub shutdown_timeout: u64,       // seconds
+    pub rate_limit_requests: u32,    // per client address, 0 for unlimited
+    pub rate_limit_bytes: u32,       // per client address, 0 for unlimited
+    pub user_key: Option<String>,    // shared secrets, authentication is off without either
+    pub admin_key: Option<String>,
+    pub stats_file: Option<PathBuf>, // stats aren't saved when not set
+    pub stats_interval: u64,         // seconds, 0 to only save on shutdown
+    pub capture_dir: Option<PathBuf>, // connections aren't recorded when not set
+    pub algorithms: Vec<Algorithm>,
+    pub log_level: LevelFilter,
+    pub log_file: Option<PathBuf>, // stderr when not set
+    pub log_max_size: u64,         // bytes, 0 to never rotate
+    pub log_max_files: usize,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            listen: vec![ListenAddr::Tcp(SocketAddr::from(([0u16; 8], 4000)))], // dual stack [::]:4000
+            allow: Vec::new(),
+            deny: Vec::new(),
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None,
+            metrics: None,
+            max_payload: 1 << 14, // 16 KiB
+            max_connections: 0,
+            busy_action: BusyAction::Queue,
+            packet_timeout: None,
+            idle_timeout: 0,
+            shutdown_timeout: 30,
+            rate_limit_requests: 0,
+            rate_limit_bytes: 0,
+            user_key: None,
+            admin_key: None,
+            stats_file: None,
+            stats_interval: 60,
+            capture_dir: None,
+            algorithms: vec![Algorithm::Prefix],
+            log_level: LevelFilter::Info,
+            log_file: None,
+            log_max_size: 10 << 20, // 10 MiB
+            log_max_files: 5,
+        }
+    }
+}
+
+impl Config {
+    /// Reads the config file named in args, if any, then applies command line overrides.
+    pub fn load(args: &Args) -> Result<Config, ConfigError> {
+        let mut config = match &args.config {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default(),
+        };
+
+        if !args.listen.is_empty() {
+            config.listen = args.listen.clone();
+        }
+        if !args.allow.is_empty() {
+            config.allow = args.allow.clone();
+        }
+        if !args.deny.is_empty() {
+            config.deny = args.deny.clone();
+        }
+        if args.tls_cert.is_some() {
+            config.tls_cert = args.tls_cert.clone();
+        }
+        if args.tls_key.is_some() {
+            config.tls_key = args.tls_key.clone();
+        }
+        if args.tls_client_ca.is_some() {
+            config.tls_client_ca = args.tls_client_ca.clone();
+        }
+        if args.metrics.is_some() {
+            config.metrics = args.metrics;
+        }
+        if let Some(max_payload) = args.max_payload {
+            config.max_payload = max_payload;
+        }
+        if let Some(max_connections) = args.max_connections {
+            config.max_connections = max_connections;
+        }
+        if let Some(busy_action) = args.busy_action {
+            config.busy_action = busy_action;
+        }
+        if let Some(packet_timeout) = args.packet_timeout {
+            config.packet_timeout = Some(packet_timeout);
+        }
+        if let Some(idle_timeout) = args.idle_timeout {
+            config.idle_timeout = idle_timeout;
+        }
+        if let Some(shutdown_timeout) = args.shutdown_timeout {
+            config.shutdown_timeout = shutdown_timeout;
+        }
+        if let Some(rate_limit_requests) = args.rate_limit_requests {
+            config.rate_limit_requests = rate_limit_requests;
+        }
+        if let Some(rate_limit_bytes) = args.rate_limit_bytes {
+            config.rate_limit_bytes = rate_limit_bytes;
+        }
+        if args.stats_file.is_some() {
+            config.stats_file = args.stats_file.clone();
+        }
+        if let Some(stats_interval) = args.stats_interval {
+            config.stats_interval = stats_interval;
+        }
+        if args.capture_dir.is_some() {
+            config.capture_dir = args.capture_dir.clone();
+        }
+        if !args.algorithms.is_empty() {
+            config.algorithms = args.algorithms.clone();
+        }
+        if let Some(log_level) = args.log_level {
+            config.log_level = log_level;
+        }
+        if args.log_file.is_some() {
+            config.log_file = args.log_file.clone();
+        }
+        if let Some(log_max_size) = args.log_max_size {
+            config.log_max_size = log_max_size;
+        }
+        if let Some(log_max_files) = args.log_max_files {
+            config.log_max_files = log_max_files;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Config, ConfigError> {
+        let contents =
+            fs::read_to_string(path).map_err(|error| ConfigError::Io(path.to_path_buf(), error))?;
+        toml::from_str(&contents).map_err(|error| ConfigError::Parse(path.to_path_buf(), error))
+    }
+
+    /// Returns the names of options that differ in new but only take effect on a restart.
+    pub fn restart_required(&self, new: &Config) -> Vec<&'static str> {
+        let options = [
+            ("listen", self.listen != new.listen),
+            ("tls_cert", self.tls_cert != new.tls_cert),
+            ("tls_key", self.tls_key != new.tls_key),
+            ("tls_client_ca", self.tls_client_ca != new.tls_client_ca),
+            ("metrics", self.metrics != new.metrics),
+            (
+                "max_connections",
+                self.max_connections != new.max_connections,
+            ),
+            ("stats_file", self.stats_file != new.stats_file),
+            ("stats_interval", self.stats_interval != new.stats_interval),
+            ("log_file", self.log_file != new.log_file),
+            ("log_max_size", self.log_max_size != new.log_max_size),
+            ("log_max_files", self.log_max_files != new.log_max_files),
+        ];
+        options
+            .iter()
+            .filter(|(_, changed)| *changed)
+            .map(|(name, _)| *name)
+            .collect()
+    }
+
+    /// Seconds to wait for the rest of a partially received packet, 0 for no timeout.
+    /// Unless set, 30 seconds or the idle timeout, whichever is shorter.
+    pub fn packet_timeout(&self) -> u64 {
+        match self.packet_timeout {
+            Some(packet_timeout) => packet_timeout,
+            None if self.idle_timeout > 0 => self.idle_timeout.min(30),
+            None => 30,
+        }
+    }
+
+    /// Checks option values that can't be expressed by their types.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.listen.is_empty() {
+            return Err(ConfigError::Invalid(String::from(
+                "at least one listen address is required",
+            )));
+        }
+
+        let tls = self
+            .listen
+            .iter()
+            .any(|addr| matches!(addr, ListenAddr::Tls(_)));
+        if tls && (self.tls_cert.is_none() || self.tls_key.is_none()) {
+            return Err(ConfigError::Invalid(String::from(
+                "tls listen addresses need a tls_cert and tls_key",
+            )));
+        }
+
+        // checked here too, so a bad value fails at startup instead of per connection
+        if !PacketCodec::MAX_PAYLOAD.contains(&self.max_payload) {
+            return Err(ConfigError::Invalid(format!(
+                "max_payload {} is not from 4096 up to 32767 bytes",
+                self.max_payload
+            )));
+        }
+
+        // the default packet timeout is capped instead, so only check one that was set
+        if let Some(packet_timeout) = self.packet_timeout {
+            if packet_timeout != 0 && self.idle_timeout != 0 && packet_timeout > self.idle_timeout {
+                return Err(ConfigError::Invalid(format!(
+                    "packet_timeout {} is longer than idle_timeout {}",
+                    packet_timeout, self.idle_timeout
+                )));
+            }
+        }
+
+        // every Compress request would fail with UnsupportedRequestType
+        if self.algorithms.is_empty() {
+            return Err(ConfigError::Invalid(String::from(
+                "at least one algorithm is required",
+            )));
+        }
+
+        for (name, key) in [("user_key", &self.user_key), ("admin_key", &self.admin_key)] {
+            if key.as_ref().is_some_and(|key| key.is_empty()) {
+                return Err(ConfigError::Invalid(format!("{} is empty", name)));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, error) => write!(f, "{}: {}", path.display(), error),
+            ConfigError::Parse(path, error) => write!(f, "{}: {}", path.display(), error),
+            ConfigError::Invalid(reason) => write!(f, "invalid config: {}", reason),
+        }
+    }
+}
+
+impl error::Error for ConfigError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(flags: &[&str]) -> Args {
+        Args::from_iter(std::iter::once("compression-service").chain(flags.iter().cloned()))
+    }
+
+    #[test]
+    fn defaults() {
+        let config = Config::load(&args(&[])).unwrap();
+        assert_eq!(config.listen, vec!["[::]:4000".parse().unwrap()]);
+        assert_eq!(config.max_payload, 16 * 1024);
+        assert_eq!(config.algorithms, vec![Algorithm::Prefix]);
+    }
+
+    #[test]
+    fn parse_file() {
+        let config: Config = toml::from_str(
+            r#"
+            listen = ["127.0.0.1:4001", "unix:/run/compression.sock"]
+            allow = ["10.0.0.0/8", "fd00::/8"]
+            deny = ["10.0.0.0/24"]
+            tls_cert = "/etc/compression-service/cert.pem"
+            tls_key = "/etc/compression-service/key.pem"
+            metrics = "[::1]:9100"
+            max_payload = 8192
+            max_connections = 64
+            busy_action = "reject"
+            packet_timeout = 5
+            idle_timeout = 60
+            rate_limit_requests = 100
+            rate_limit_bytes = 65536
+            user_key = "user secret"
+            admin_key = "admin secret"
+            stats_file = "/var/lib/compression-service/stats.json"
+            stats_interval = 300
+            capture_dir = "/var/lib/compression-service/captures"
+            algorithms = ["prefix"]
+            log_level = "debug"
+            log_file = "/var/log/compression-service.log"
+            log_max_size = 1048576
+            log_max_files = 3
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.listen,
+            vec![
+                "127.0.0.1:4001".parse().unwrap(),
+                "unix:/run/compression.sock".parse().unwrap()
+            ]
+        );
+        assert_eq!(config.allow.len(), 2);
+        assert_eq!(config.deny, vec!["10.0.0.0/24".parse::<IpNet>().unwrap()]);
+        assert_eq!(
+            config.tls_cert,
+            Some(PathBuf::from("/etc/compression-service/cert.pem"))
+        );
+        assert_eq!(config.tls_client_ca, None);
+        assert_eq!(config.metrics, Some("[::1]:9100".parse().unwrap()));
+        assert_eq!(config.max_payload, 8192);
+        assert_eq!(config.max_connections, 64);
+        assert_eq!(config.busy_action, BusyAction::Reject);
+        assert_eq!(config.rate_limit_requests, 100);
+        assert_eq!(config.rate_limit_bytes, 65536);
+        assert_eq!(config.user_key.as_deref(), Some("user secret"));
+        assert_eq!(config.admin_key.as_deref(), Some("admin secret"));
+        assert_eq!(
+            config.stats_file,
+            Some(PathBuf::from("/var/lib/compression-service/stats.json"))
+        );
+        assert_eq!(config.stats_interval, 300);
+        assert_eq!(
+            config.capture_dir,
+            Some(PathBuf::from("/var/lib/compression-service/captures"))
+        );
+        assert_eq!(config.log_level, LevelFilter::Debug);
+        assert_eq!(
+            config.log_file,
+            Some(PathBuf::from("/var/log/compression-service.log"))
+        );
+        assert_eq!(config.log_max_size, 1 << 20);
+        assert_eq!(config.log_max_files, 3);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn unknown_field() {
+        assert!(toml::from_str::<Config>("max_paylod = 8192").is_err());
+    }
+
+    #[test]
+    fn flags_override() {
+        let config = Config::load(&args(&[
+            "--listen",
+            "[::1]:5000",
+            "--listen",
+            "unix:compression.sock",
+            "--max-payload",
+            "4096",
+        ]))
+        .unwrap();
+        assert_eq!(config.listen.len(), 2);
+        assert_eq!(config.max_payload, 4096);
+    }
+
+    #[test]
+    fn max_payload_out_of_range() {
+        assert!(Config::load(&args(&["--max-payload", "4095"])).is_err());
+        assert!(Config::load(&args(&["--max-payload", "32768"])).is_err());
+    }
+
+    #[test]
+    fn tls_without_cert() {
+        assert!(Config::load(&args(&["--listen", "tls:[::]:4443"])).is_err());
+        assert!(Config::load(&args(&[
+            "--listen",
+            "tls:[::]:4443",
+            "--tls-cert",
+            "cert.pem",
+            "--tls-key",
+            "key.pem"
+        ]))
+        .is_ok());
+    }
+
+    #[test]
+    fn no_algorithms() {
+        let config: Config = toml::from_str("algorithms = []").unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn restart_required() {
+        let config = Config::default();
+        let new = Config {
+            max_payload: 8192,
+            max_connections: 10,
+            metrics: Some("[::1]:9100".parse().unwrap()),
+            stats_file: Some(PathBuf::from("stats.json")),
+            stats_interval: 10,
+            ..Config::default()
+        };
+        assert!(config.restart_required(&Config::default()).is_empty());
+        assert_eq!(
+            config.restart_required(&new),
+            vec!["metrics", "max_connections", "stats_file", "stats_interval"]
+        );
+    }
+
+    #[test]
+    fn packet_timeout_longer_than_idle() {
+        assert!(Config::load(&args(&["--packet-timeout", "10", "--idle-timeout", "5"])).is_err());
+    }
+
+    #[test]
+    fn idle_timeout_alone() {
+        let config = Config::load(&args(&["--idle-timeout", "10"])).unwrap();
+        assert_eq!(config.packet_timeout(), 10);
+        let config = Config::load(&args(&["--idle-timeout", "60"])).unwrap();
+        assert_eq!(config.packet_timeout(), 30);
+        assert_eq!(Config::default().packet_timeout(), 30);
+
+        let config = Config::load(&args(&["--packet-timeout", "0", "--idle-timeout", "10"]));
+        assert_eq!(config.unwrap().packet_timeout(), 0);
+    }
+}
@@ -0,0 +1,235 @@
+use serde::Deserialize;
+use std::convert::TryFrom;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
+use std::path::PathBuf;
+use std::{fmt, fs, io, net};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+/// Where to accept clients, parsed from `[::]:4000`, `tls:[::]:4443`, or
+/// `unix:/path/to/socket`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum ListenAddr {
+    Tcp(net::SocketAddr),
+    Tls(net::SocketAddr),
+    Unix(PathBuf),
+}
+
+impl std::str::FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err(String::from("unix socket path is empty"));
+            }
+            Ok(ListenAddr::Unix(PathBuf::from(path)))
+        } else if let Some(addr) = s.strip_prefix("tls:") {
+            addr.parse()
+                .map(ListenAddr::Tls)
+                .map_err(|_| format!("'{}' is not an address and port", addr))
+        } else {
+            s.parse().map(ListenAddr::Tcp).map_err(|_| {
+                format!(
+                    "'{}' is not an address and port, tls:address and port, or unix:path",
+                    s
+                )
+            })
+        }
+    }
+}
+
+impl TryFrom<String> for ListenAddr {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Tls(addr) => write!(f, "tls:{}", addr),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// A bound TCP, TLS, or Unix domain socket listener.
+pub enum Listener {
+    Tcp(TcpListener),
+    Tls(TcpListener, TlsAcceptor),
+    Unix(UnixListener, Option<PathBuf>), // keep the path to remove the socket file on drop
+}
+
+/// An accepted client connection. TLS clients still have to finish their handshake.
+pub enum Socket {
+    Tcp(TcpStream),
+    Tls(TcpStream, TlsAcceptor),
+    Unix(UnixStream),
+}
+
+/// A client connection ready for packets. Every variant implements AsyncRead + AsyncWrite.
+pub enum Stream {
+    Tcp(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+    Unix(UnixStream),
+}
+
+impl Listener {
+    /// Binds addr. TLS addresses need an acceptor with the server certificate.
+    pub async fn bind(addr: &ListenAddr, tls: Option<&TlsAcceptor>) -> io::Result<Listener> {
+        match addr {
+            ListenAddr::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr).await?)),
+            ListenAddr::Tls(addr) => {
+                let acceptor = tls.cloned().ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "tls listener without a certificate",
+                    )
+                })?;
+                Ok(Listener::Tls(TcpListener::bind(addr).await?, acceptor))
+            }
+            ListenAddr::Unix(path) => {
+                // a socket file left behind by a previous run would fail the bind,
+                // but don't clobber anything that isn't a socket
+                if let Ok(metadata) = fs::symlink_metadata(path) {
+                    if metadata.file_type().is_socket() {
+                        fs::remove_file(path)?;
+                    }
+                }
+
+                Ok(Listener::Unix(
+                    UnixListener::bind(path)?,
+                    Some(path.clone()),
+                ))
+            }
+        }
+    }
+
+    /// Takes over a listening socket inherited from a service manager. TCP sockets
+    /// accept TLS clients when given an acceptor.
+    ///
+    /// # Safety
+    ///
+    /// fd must be an open listening TCP or Unix domain socket that nothing else owns.
+    pub unsafe fn from_fd(fd: RawFd, tls: Option<&TlsAcceptor>) -> io::Result<Listener> {
+        let tcp = net::TcpListener::from_raw_fd(fd);
+        if tcp.local_addr().is_ok() {
+            tcp.set_nonblocking(true)?;
+            let listener = TcpListener::from_std(tcp)?;
+            return Ok(match tls {
+                Some(acceptor) => Listener::Tls(listener, acceptor.clone()),
+                None => Listener::Tcp(listener),
+            });
+        }
+
+        // not an inet socket, so it had better be a unix one
+        let unix = std::os::unix::net::UnixListener::from_raw_fd(tcp.into_raw_fd());
+        unix.local_addr()?;
+        unix.set_nonblocking(true)?;
+        // the service manager owns the socket file, so leave it alone on drop
+        Ok(Listener::Unix(UnixListener::from_std(unix)?, None))
+    }
+
+    /// Describes where clients are accepted, for log messages.
+    pub fn local_addr(&self) -> String {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().map(|addr| addr.to_string()),
+            Listener::Tls(listener, _) => listener.local_addr().map(|addr| format!("tls:{}", addr)),
+            Listener::Unix(listener, _) => {
+                listener.local_addr().map(|addr| match addr.as_pathname() {
+                    Some(path) => format!("unix:{}", path.display()),
+                    None => String::from("unix:unnamed"),
+                })
+            }
+        }
+        .unwrap_or_else(|error| error.to_string())
+    }
+
+    /// Waits for the next client. Returns the peer address for TCP and TLS clients.
+    pub async fn accept(&mut self) -> io::Result<(Socket, Option<net::SocketAddr>)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (socket, addr) = listener.accept().await?;
+                Ok((Socket::Tcp(socket), Some(addr)))
+            }
+            Listener::Tls(listener, acceptor) => {
+                // don't hold up the accept loop with the handshake
+                let (socket, addr) = listener.accept().await?;
+                Ok((Socket::Tls(socket, acceptor.clone()), Some(addr)))
+            }
+            Listener::Unix(listener, _) => {
+                let (socket, _addr) = listener.accept().await?; // unix clients are usually unnamed
+                Ok((Socket::Unix(socket), None))
+            }
+        }
+    }
+}
+
+impl Socket {
+    /// Finishes the TLS handshake of TLS clients. Other clients are ready right away.
+    pub async fn handshake(self) -> io::Result<Stream> {
+        match self {
+            Socket::Tcp(socket) => Ok(Stream::Tcp(socket)),
+            Socket::Tls(socket, acceptor) => {
+                Ok(Stream::Tls(Box::new(acceptor.accept(socket).await?)))
+            }
+            Socket::Unix(socket) => Ok(Stream::Unix(socket)),
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, Some(path)) = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_tcp() {
+        assert_eq!(
+            "[::1]:4000".parse(),
+            Ok(ListenAddr::Tcp("[::1]:4000".parse().unwrap()))
+        );
+        assert_eq!(
+            "127.0.0.1:4000".parse(),
+            Ok(ListenAddr::Tcp("127.0.0.1:4000".parse().unwrap()))
+        );
+    }
+
+    #[test]
+    fn parse_tls() {
+        assert_eq!(
+            "tls:[::]:4443".parse(),
+            Ok(ListenAddr::Tls("[::]:4443".parse().unwrap()))
+        );
+        assert!("tls:localhost:4443".parse::<ListenAddr>().is_err());
+    }
+
+    #[test]
+    fn parse_unix() {
+        assert_eq!(
+            "unix:/run/compression.sock".parse(),
+            Ok(ListenAddr::Unix(PathBuf::from("/run/compression.sock")))
+        );
+        assert!("unix:".parse::<ListenAddr>().is_err());
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert!("localhost".parse::<ListenAddr>().is_err());
+        assert!(":::4000".parse::<ListenAddr>().is_err());
+    }
+}
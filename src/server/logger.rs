@@ -0,0 +1,220 @@
+use log::kv::{self, VisitSource};
+use log::{LevelFilter, Log, Metadata, Record};
+use serde_json::{Map, Value};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Where log lines are written.
+enum Output {
+    Stderr,
+    File(RotatingFile),
+}
+
+/// A log file that is renamed to path.1 once it grows past max_size bytes. Older
+/// files are renamed to path.2 and so on, up to path.max_files.
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64, // 0 to never rotate
+    max_files: usize,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, max_size: u64, max_files: usize) -> io::Result<RotatingFile> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            file,
+            size,
+            max_size,
+            max_files,
+        })
+    }
+
+    fn rotated(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        PathBuf::from(path)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            // the oldest file is overwritten by the one before it
+            for index in (1..self.max_files).rev() {
+                let from = self.rotated(index);
+                if from.exists() {
+                    fs::rename(from, self.rotated(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.max_size > 0 && self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+/// Writes each log record as a line of JSON, with the time, level, message, and any
+/// key values of the record.
+struct Logger {
+    output: Mutex<Output>,
+}
+
+/// Collects the key values of a record into a JSON object.
+struct Fields<'a>(&'a mut Map<String, Value>);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        let value = serde_json::to_value(&value).map_err(kv::Error::boxed)?;
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+/// Formats a record as a single line of JSON, including the trailing newline.
+fn format(record: &Record, time: SystemTime) -> String {
+    let mut fields = Map::new();
+    fields.insert(
+        String::from("time"),
+        Value::from(humantime::format_rfc3339_millis(time).to_string()),
+    );
+    fields.insert(
+        String::from("level"),
+        Value::from(record.level().as_str().to_lowercase()),
+    );
+    fields.insert(
+        String::from("message"),
+        Value::from(record.args().to_string()),
+    );
+    let _ = record.key_values().visit(&mut Fields(&mut fields));
+
+    let mut line = Value::Object(fields).to_string();
+    line.push('\n');
+    line
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let line = format(record, SystemTime::now());
+        let mut output = self.output.lock().unwrap();
+        match &mut *output {
+            Output::Stderr => {
+                let _ = io::stderr().write_all(line.as_bytes());
+            }
+            Output::File(file) => {
+                // there's nowhere else to log a failed write, so fall back to stderr
+                if let Err(error) = file.write_line(line.as_bytes()) {
+                    eprintln!("error: {}: {}", file.path.display(), error);
+                    let _ = io::stderr().write_all(line.as_bytes());
+                }
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Output::File(file) = &mut *self.output.lock().unwrap() {
+            let _ = file.file.flush();
+        }
+    }
+}
+
+/// Installs the logger, writing to stderr, or to file when it's set. Only the first
+/// call has any effect.
+pub fn init(
+    level: LevelFilter,
+    file: Option<&Path>,
+    max_size: u64,
+    max_files: usize,
+) -> io::Result<()> {
+    let output = match file {
+        Some(path) => Output::File(RotatingFile::open(path, max_size, max_files).map_err(
+            |error| io::Error::new(error.kind(), format!("{}: {}", path.display(), error)),
+        )?),
+        None => Output::Stderr,
+    };
+
+    let logger = Logger {
+        output: Mutex::new(output),
+    };
+    if log::set_boxed_logger(Box::new(logger)).is_ok() {
+        log::set_max_level(level);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::time::Duration;
+
+    #[test]
+    fn json_line() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_millis(1500);
+        let kvs = [("peer", "::1"), ("request", "ping")];
+        let record = Record::builder()
+            .level(log::Level::Info)
+            .args(format_args!("request \"done\""))
+            .key_values(&kvs)
+            .build();
+        assert_eq!(
+            format(&record, time),
+            "{\"time\":\"1970-01-01T00:00:01.500Z\",\"level\":\"info\",\
+             \"message\":\"request \\\"done\\\"\",\"peer\":\"::1\",\"request\":\"ping\"}\n"
+        );
+    }
+
+    #[test]
+    fn rotate() {
+        let dir = env::temp_dir().join(format!("compression-service-log-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("service.log");
+
+        let mut file = RotatingFile::open(&path, 10, 2).unwrap();
+        for line in &["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write_line(line.as_bytes()).unwrap();
+        }
+
+        // each line is over half the max size, so every line starts a new file
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(
+            fs::read_to_string(dir.join("service.log.1")).unwrap(),
+            "third\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("service.log.2")).unwrap(),
+            "second\n"
+        );
+        assert!(!dir.join("service.log.3").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,356 @@
+use super::stats::Stats;
+use crate::message::RequestCode;
+
+use bytes::{BufMut, BytesMut};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io;
+use std::net::Shutdown;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tokio::time;
+
+/// Number of bounded histogram buckets. Bucket i counts samples less than or equal to 2^i.
+const BUCKETS: usize = 25;
+
+/// How long a client has to send its request head.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait before accepting again after an accept error.
+const ACCEPT_RETRY: Duration = Duration::from_millis(100);
+
+/// Histogram with fixed log-scale buckets.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Histogram {
+    buckets: [usize; BUCKETS + 1], // the last bucket counts samples above 2^(BUCKETS - 1)
+    sum: u64,
+    count: usize,
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram::new()
+    }
+}
+
+impl Histogram {
+    pub fn new() -> Histogram {
+        Histogram {
+            buckets: [0; BUCKETS + 1],
+            sum: 0,
+            count: 0,
+        }
+    }
+
+    pub fn record(&mut self, value: u64) {
+        // index of the smallest power of two greater than or equal to value
+        let index = if value <= 1 {
+            0
+        } else {
+            (64 - (value - 1).leading_zeros()) as usize
+        };
+
+        self.buckets[index.min(BUCKETS)] += 1;
+        self.sum += value;
+        self.count += 1;
+    }
+
+    /// Writes the sample count, sample sum, then every bucket count in big-endian order.
+    pub fn encode(&self, buffer: &mut BytesMut) {
+        buffer.reserve(Histogram::ENCODED_LEN);
+        buffer.put_u32(self.count as u32);
+        buffer.put_u64(self.sum);
+        for count in self.buckets.iter() {
+            buffer.put_u32(*count as u32);
+        }
+    }
+
+    /// Length of an encoded histogram in bytes.
+    pub const ENCODED_LEN: usize = 4 + 8 + 4 * (BUCKETS + 1);
+}
+
+/// Writes histograms in Prometheus text format with one series per request type. Bucket
+/// bounds and sums are divided by units, ie. 1e6 to convert recorded microseconds to seconds.
+fn render_histograms(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    units: f64,
+    histograms: &BTreeMap<u16, Histogram>,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} histogram", name);
+
+    for (request, histogram) in histograms {
+        let request = RequestCode::name(*request);
+
+        // Prometheus buckets are cumulative
+        let mut total = 0;
+        for (i, count) in histogram.buckets.iter().take(BUCKETS).enumerate() {
+            total += count;
+            let bound = (1u64 << i) as f64 / units;
+            let _ = writeln!(
+                out,
+                "{}_bucket{{request=\"{}\",le=\"{}\"}} {}",
+                name, request, bound, total
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{request=\"{}\",le=\"+Inf\"}} {}",
+            name, request, histogram.count
+        );
+        let _ = writeln!(
+            out,
+            "{}_sum{{request=\"{}\"}} {}",
+            name,
+            request,
+            histogram.sum as f64 / units
+        );
+        let _ = writeln!(
+            out,
+            "{}_count{{request=\"{}\"}} {}",
+            name, request, histogram.count
+        );
+    }
+}
+
+fn render_metric(out: &mut String, name: &str, kind: &str, help: &str, value: usize) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// Formats global stats in the Prometheus text exposition format.
+pub fn render(stats: &Stats) -> String {
+    let mut out = String::new();
+
+    render_metric(
+        &mut out,
+        "compression_service_received_bytes_total",
+        "counter",
+        "Total packet bytes received.",
+        stats.received,
+    );
+    render_metric(
+        &mut out,
+        "compression_service_sent_bytes_total",
+        "counter",
+        "Total packet bytes sent.",
+        stats.sent,
+    );
+    render_metric(
+        &mut out,
+        "compression_service_payload_before_bytes_total",
+        "counter",
+        "Total payload bytes before compression.",
+        stats.before,
+    );
+    render_metric(
+        &mut out,
+        "compression_service_payload_after_bytes_total",
+        "counter",
+        "Total payload bytes after compression.",
+        stats.after,
+    );
+    render_metric(
+        &mut out,
+        "compression_service_active_connections",
+        "gauge",
+        "Currently open client connections.",
+        stats.connections,
+    );
+    render_metric(
+        &mut out,
+        "compression_service_rejected_connections_total",
+        "counter",
+        "Connections rejected because max connections were already open.",
+        stats.rejected,
+    );
+    render_metric(
+        &mut out,
+        "compression_service_denied_connections_total",
+        "counter",
+        "Connections denied by the allow and deny rules.",
+        stats.denied,
+    );
+    render_metric(
+        &mut out,
+        "compression_service_packet_timeouts_total",
+        "counter",
+        "Partially received packets given up on after the packet timeout.",
+        stats.packet_timeouts,
+    );
+    render_metric(
+        &mut out,
+        "compression_service_idle_timeouts_total",
+        "counter",
+        "Connections closed after the idle timeout.",
+        stats.idle_timeouts,
+    );
+
+    let name = "compression_service_responses_total";
+    let _ = writeln!(out, "# HELP {} Responses sent by status code.", name);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    for (code, count) in &stats.statuses {
+        let _ = writeln!(out, "{}{{code=\"{}\"}} {}", name, code, count);
+    }
+
+    render_histograms(
+        &mut out,
+        "compression_service_request_duration_seconds",
+        "Time from a decoded request to its sent response.",
+        1e6, // recorded in microseconds
+        &stats.latency,
+    );
+    render_histograms(
+        &mut out,
+        "compression_service_request_payload_bytes",
+        "Request payload length.",
+        1.0,
+        &stats.payload,
+    );
+
+    out
+}
+
+/// Serves global stats over HTTP at /metrics.
+pub async fn serve(mut listener: TcpListener, stats: Arc<Mutex<Stats>>) {
+    loop {
+        let socket = match listener.accept().await {
+            Ok((socket, _addr)) => socket,
+            Err(error) => {
+                // ie. out of file descriptors, which frees up once other sockets close
+                log::error!(error = error.to_string().as_str(); "metrics accept failed");
+                time::delay_for(ACCEPT_RETRY).await;
+                continue;
+            }
+        };
+        let stats = stats.clone();
+
+        tokio::spawn(async move { respond(socket, stats).await });
+    }
+}
+
+async fn respond(mut socket: TcpStream, stats: Arc<Mutex<Stats>>) -> io::Result<()> {
+    // a client that never finishes its request doesn't get to keep the socket
+    let request = match time::timeout(REQUEST_TIMEOUT, read_request(&mut socket)).await {
+        Ok(request) => request?,
+        Err(_) => return Ok(()),
+    };
+    let request = match request {
+        Some(request) => request,
+        None => return Ok(()),
+    };
+
+    // request line looks like: GET /metrics HTTP/1.1
+    let line = String::from_utf8_lossy(&request);
+    let mut parts = line.split_whitespace();
+    let method = parts.next();
+    let path = parts
+        .next()
+        .map(|path| path.split('?').next().unwrap_or(path));
+
+    let (status, body) = match (method, path) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render(&*stats.lock().await)),
+        _ => ("404 Not Found", String::from("not found\n")),
+    };
+
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    );
+    socket.write_all(head.as_bytes()).await?;
+    socket.write_all(body.as_bytes()).await?;
+    socket.shutdown(Shutdown::Write)
+}
+
+/// Reads until the end of the request head, we don't expect a request body. Returns None
+/// when the client closes first or sends an unreasonably large request.
+async fn read_request(socket: &mut TcpStream) -> io::Result<Option<Vec<u8>>> {
+    let mut request = Vec::new();
+    let mut chunk = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let count = socket.read(&mut chunk).await?;
+        if count == 0 || request.len() > (1 << 13) {
+            return Ok(None);
+        }
+        request.extend_from_slice(&chunk[..count]);
+    }
+    Ok(Some(request))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets() {
+        let mut histogram = Histogram::new();
+        for value in &[0, 1, 2, 3, 4, 5, 1 << 30] {
+            histogram.record(*value);
+        }
+        assert_eq!(&histogram.buckets[..4], &[2, 1, 2, 1]); // 0 and 1, 2, 3 and 4, 5
+        assert_eq!(histogram.buckets[BUCKETS], 1); // 2^30 overflows the last bound
+        assert_eq!(histogram.count, 7);
+        assert_eq!(histogram.sum, 15 + (1 << 30));
+    }
+
+    #[test]
+    fn encode() {
+        let mut histogram = Histogram::new();
+        histogram.record(3);
+
+        let mut buffer = BytesMut::new();
+        histogram.encode(&mut buffer);
+        assert_eq!(buffer.len(), Histogram::ENCODED_LEN);
+        assert_eq!(
+            &buffer[..20],
+            b"\0\0\0\x01\0\0\0\0\0\0\0\x03\0\0\0\0\0\0\0\0"
+        );
+        assert_eq!(&buffer[20..24], b"\0\0\0\x01"); // 3 is in the bucket up to 4
+    }
+
+    #[test]
+    fn render_stats() {
+        let mut stats = Stats::new();
+        stats.received = 8;
+        stats.connections = 1;
+        stats.statuses.insert(0, 3);
+        stats.statuses.insert(33, 1);
+        stats.record(4, 0, 3, 5);
+
+        let out = render(&stats);
+        assert!(out.contains("compression_service_received_bytes_total 8\n"));
+        assert!(out.contains("compression_service_active_connections 1\n"));
+        assert!(out.contains("compression_service_responses_total{code=\"0\"} 4\n"));
+        assert!(out.contains("compression_service_responses_total{code=\"33\"} 1\n"));
+
+        let name = "compression_service_request_duration_seconds";
+        let has = |line: String| out.lines().any(|l| l == line);
+        assert!(has(format!(
+            "{}_bucket{{request=\"compress\",le=\"0.000002\"}} 0",
+            name
+        )));
+        assert!(has(format!(
+            "{}_bucket{{request=\"compress\",le=\"0.000004\"}} 1",
+            name
+        )));
+        assert!(has(format!(
+            "{}_bucket{{request=\"compress\",le=\"+Inf\"}} 1",
+            name
+        )));
+        assert!(has(format!("{}_count{{request=\"compress\"}} 1", name)));
+
+        let name = "compression_service_request_payload_bytes";
+        assert!(has(format!(
+            "{}_bucket{{request=\"compress\",le=\"8\"}} 1",
+            name
+        )));
+        assert!(has(format!("{}_sum{{request=\"compress\"}} 5", name)));
+    }
+}
@@ -0,0 +1,909 @@
+mod access;
+mod auth;
+mod capture;
+pub mod config;
+pub mod listener;
+pub mod logger;
+pub mod metrics;
+pub mod persist;
+pub mod ratelimit;
+pub mod replay;
+pub mod shutdown;
+pub mod stats;
+pub mod systemd;
+pub mod tls;
+
+use self::access::AccessList;
+use self::auth::{Keys, Role};
+use self::capture::{Capture, Recorder};
+use self::config::{Algorithm, BusyAction, Config};
+use self::listener::{Listener, Socket, Stream};
+use self::ratelimit::RateLimiter;
+use self::shutdown::Shutdown;
+use self::stats::Stats;
+use crate::compress::Compressor;
+use crate::message::{RequestCode, StatusCode};
+use crate::packet::PacketCodec;
+
+use bytes::{BufMut, BytesMut};
+use futures::sink::SinkExt;
+use std::io;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::stream::StreamExt;
+use tokio::sync::{watch, Mutex, Semaphore};
+use tokio::time;
+use tokio_util::codec::Framed;
+
+/// Identifies connections in log messages.
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// State shared by every listener of a server: the global stats, the latest config,
+/// and the connection and rate limits.
+#[derive(Clone)]
+pub struct Server {
+    stats: Arc<Mutex<Stats>>,
+    config: watch::Receiver<Arc<Config>>,
+    limit: Option<Arc<Semaphore>>, // one connection slot per permit
+    limiter: Arc<RateLimiter>,     // request and byte buckets per client address
+}
+
+impl Server {
+    /// Creates a server with the limits of the current config. New connections use the
+    /// latest config sent on the channel, and open ones pick it up between requests.
+    pub fn new(stats: Arc<Mutex<Stats>>, config: watch::Receiver<Arc<Config>>) -> Server {
+        let current = config.borrow().clone();
+        let limit = if current.max_connections > 0 {
+            Some(Arc::new(Semaphore::new(current.max_connections)))
+        } else {
+            None
+        };
+        let limiter = Arc::new(RateLimiter::new(
+            current.rate_limit_requests,
+            current.rate_limit_bytes,
+        ));
+
+        Server {
+            stats,
+            config,
+            limit,
+            limiter,
+        }
+    }
+
+    /// The rate limiter, whose rates aren't part of the config channel.
+    pub fn limiter(&self) -> &RateLimiter {
+        &self.limiter
+    }
+
+    /// Answers the requests of a client that's already connected, like a unix client,
+    /// until it closes the connection or a graceful shutdown starts.
+    pub async fn handle<S>(&self, socket: S, mut shutdown: Shutdown) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        handle_connection(
+            socket,
+            &Client::new(None),
+            self.stats.clone(),
+            self.config.clone(),
+            self.limiter.clone(),
+            &mut shutdown,
+        )
+        .await
+    }
+
+    /// Spawns a task to handle each client accepted by listener, until a graceful
+    /// shutdown starts. Dropping the listener on return stops accepting, while the
+    /// spawned tasks hold a clone of shutdown until their connection is closed.
+    pub async fn serve(self, mut listener: Listener, mut shutdown: Shutdown) -> io::Result<()> {
+        let Server {
+            stats,
+            config: shared_config,
+            limit,
+            limiter,
+        } = self;
+
+        loop {
+            let config = shared_config.borrow().clone(); // the latest config
+
+            // when queueing, leave new clients in the listen backlog until a slot is free
+            let next = async {
+                let permit = match (&limit, config.busy_action) {
+                    (Some(limit), BusyAction::Queue) => Some(limit.clone().acquire_owned().await),
+                    _ => None,
+                };
+                (permit, listener.accept().await)
+            };
+            let (mut permit, accepted) = tokio::select! {
+                next = next => next,
+                _ = shutdown.wait() => return Ok(()),
+            };
+
+            let (socket, addr) = accepted?;
+            let peer = addr.map(|addr| addr.ip().to_canonical()); // None for unix clients
+            let stats = stats.clone(); // local reference to global stats
+
+            // unix clients are only limited by socket file permissions
+            if let Some(peer) = peer {
+                // rules may have been reloaded while waiting to accept
+                let config = shared_config.borrow().clone();
+                if !AccessList::new(&config.allow, &config.deny).permits(peer) {
+                    log::warn!(peer = peer.to_string().as_str(); "denied connection");
+                    stats.lock().await.denied += 1;
+                    continue; // dropping the socket closes it
+                }
+            }
+            // when rejecting, only take a slot if one is free right now
+            if let (Some(limit), None) = (&limit, &permit) {
+                permit = limit.clone().try_acquire_owned().ok();
+                if permit.is_none() {
+                    log::warn!(
+                        "rejected connection, {} already open",
+                        config.max_connections
+                    );
+                    tokio::spawn(async move {
+                        match handshake(socket, peer, &config).await? {
+                            Stream::Tcp(socket) => reject_connection(socket, stats, config).await,
+                            Stream::Tls(socket) => reject_connection(socket, stats, config).await,
+                            Stream::Unix(socket) => reject_connection(socket, stats, config).await,
+                        }
+                    });
+                    continue;
+                }
+            }
+
+            let limiter = limiter.clone();
+            let shared_config = shared_config.clone();
+            let mut shutdown = shutdown.clone(); // held until the connection is closed
+
+            tokio::spawn(async move {
+                let _permit = permit; // frees a connection slot when dropped
+                let stream = handshake(socket, peer, &config).await?;
+                let client = Client::new(peer);
+                log::info!(conn = client.id, peer = client.name.as_str(); "connection opened");
+                let opened = Instant::now();
+                let recorder = capture(&config, &client, shutdown.clone()).await;
+
+                stats.lock().await.connections += 1;
+                let result = match stream {
+                    Stream::Tcp(socket) => {
+                        handle_connection(
+                            Capture::new(socket, recorder),
+                            &client,
+                            stats.clone(),
+                            shared_config,
+                            limiter,
+                            &mut shutdown,
+                        )
+                        .await
+                    }
+                    Stream::Tls(socket) => {
+                        handle_connection(
+                            Capture::new(socket, recorder),
+                            &client,
+                            stats.clone(),
+                            shared_config,
+                            limiter,
+                            &mut shutdown,
+                        )
+                        .await
+                    }
+                    Stream::Unix(socket) => {
+                        handle_connection(
+                            Capture::new(socket, recorder),
+                            &client,
+                            stats.clone(),
+                            shared_config,
+                            limiter,
+                            &mut shutdown,
+                        )
+                        .await
+                    }
+                };
+                stats.lock().await.connections -= 1;
+
+                let duration = opened.elapsed().as_millis() as u64;
+                match &result {
+                    Ok(()) => log::info!(
+                        conn = client.id,
+                        peer = client.name.as_str(),
+                        duration_ms = duration;
+                        "connection closed"
+                    ),
+                    Err(error) => log::info!(
+                        conn = client.id,
+                        peer = client.name.as_str(),
+                        duration_ms = duration,
+                        error = error.to_string().as_str();
+                        "connection closed"
+                    ),
+                }
+                result
+            });
+        }
+    }
+}
+
+/// Starts recording the connection of client when a capture directory is set. Errors are
+/// logged, and the connection isn't recorded.
+async fn capture(config: &Config, client: &Client, shutdown: Shutdown) -> Option<Recorder> {
+    let dir = config.capture_dir.as_ref()?;
+    match Recorder::create(dir, client.id, &client.name, shutdown).await {
+        Ok(recorder) => Some(recorder),
+        Err(error) => {
+            log::error!(
+                conn = client.id,
+                path = dir.display().to_string().as_str(),
+                error = error.to_string().as_str();
+                "failed to create capture"
+            );
+            None
+        }
+    }
+}
+
+/// Finishes the TLS handshake of TLS clients, giving up after the packet timeout.
+async fn handshake(socket: Socket, peer: Option<IpAddr>, config: &Config) -> io::Result<Stream> {
+    let result = if config.packet_timeout() > 0 {
+        let wait = Duration::from_secs(config.packet_timeout());
+        time::timeout(wait, socket.handshake())
+            .await
+            .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")))
+    } else {
+        socket.handshake().await
+    };
+
+    if let Err(error) = &result {
+        log::warn!(
+            peer = peer_name(peer).as_str(),
+            error = error.to_string().as_str();
+            "tls handshake failed"
+        );
+    }
+    result
+}
+
+/// Sends a ServerBusy status code then closes the connection.
+async fn reject_connection<S>(
+    socket: S,
+    stats: Arc<Mutex<Stats>>,
+    config: Arc<Config>,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut stream = Framed::new(
+        socket,
+        PacketCodec::new_with_max_payload(config.max_payload)?,
+    );
+    let result = stream.send(StatusCode::ServerBusy).await;
+
+    let (_, sent) = stream.codec().get_stats();
+    let mut stats = stats.lock().await;
+    stats.sent += sent;
+    stats.rejected += 1;
+
+    result
+}
+
+/// Parses requests and sends responses until the client closes the connection,
+/// or until a graceful shutdown starts and there's no request in progress.
+async fn handle_connection<S>(
+    socket: S,
+    client: &Client,
+    stats: Arc<Mutex<Stats>>,
+    mut shared_config: watch::Receiver<Arc<Config>>,
+    limiter: Arc<RateLimiter>,
+    shutdown: &mut Shutdown,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let config = shared_config.borrow().clone();
+    let mut stream = Framed::new(
+        socket,
+        PacketCodec::new_with_max_payload(config.max_payload)?,
+    );
+    let mut compressor = Compressor::new();
+    let mut idle_since = Instant::now(); // when the last response was sent
+
+    let peer = client.peer;
+
+    // every connection starts anonymous, unless authentication is off
+    let keys = Keys::new(config.user_key.as_deref(), config.admin_key.as_deref());
+    let mut role = keys.initial_role();
+    let mut challenge = None; // sent to the client, waiting to be signed
+    let mut reloads = true; // until the config sender is dropped
+
+    loop {
+        update_stats(stream.codec_mut(), &mut compressor, &stats).await;
+
+        // pick up limits from a reloaded config between requests
+        let config = shared_config.borrow().clone();
+        stream.codec_mut().set_max_payload(config.max_payload)?;
+
+        // finish reading a partially received packet before shutting down
+        let in_progress = stream.codec().packet_started().is_some();
+        if shutdown.is_started() && !in_progress {
+            break;
+        }
+
+        // wait for the next packet, but wake up in time to check for expired timeouts
+        let wait = read_timeout(stream.codec(), idle_since, &config);
+        let read = async {
+            match wait {
+                Some(wait) => time::timeout(wait, stream.next()).await.ok(),
+                None => Some(stream.next().await),
+            }
+        };
+        let read = if shutdown.is_started() {
+            read.await
+        } else {
+            tokio::select! {
+                read = read => read,
+                _ = shutdown.wait() => continue, // check for a request in progress
+                // apply new limits before decoding
+                changed = shared_config.recv(), if reloads => {
+                    reloads = changed.is_some();
+                    continue;
+                }
+            }
+        };
+
+        let next = match read {
+            Some(next) => next,
+            None => match stream.codec().packet_started() {
+                // give up on a stalled packet and look for the next magic header
+                Some(started)
+                    if config.packet_timeout() > 0
+                        && started.elapsed() >= Duration::from_secs(config.packet_timeout()) =>
+                {
+                    stream.codec_mut().reset();
+                    stats.lock().await.packet_timeouts += 1;
+                    Some(Err(StatusCode::Timeout))
+                }
+                // close a connection that hasn't started a new packet in a while
+                None if config.idle_timeout > 0
+                    && idle_since.elapsed() >= Duration::from_secs(config.idle_timeout) =>
+                {
+                    stats.lock().await.idle_timeouts += 1;
+                    stream.send(StatusCode::Timeout).await?;
+                    break;
+                }
+                _ => continue, // nothing has expired yet
+            },
+        };
+        let start = Instant::now(); // time from decoded request to sent response
+
+        // request code value and payload length, or 0 for unparsed packets
+        let (request, payload) = match &next {
+            Some(Ok(RequestCode::Compress(payload))) => (4, payload.len()),
+            Some(Ok(request)) => (request.value(), 0),
+            _ => (0, 0),
+        };
+
+        // milliseconds until a client over its rate limit may retry, rounded up
+        let retry_after = match (&next, peer) {
+            (Some(Ok(_)), Some(peer)) => match limiter.check(peer, payload) {
+                Ok(()) => 0,
+                Err(wait) => wait.as_micros().div_ceil(1000) as u32,
+            },
+            _ => 0,
+        };
+
+        let response = match next {
+            // tell clients over their rate limit when to retry instead of processing
+            Some(Ok(_)) if retry_after > 0 => StatusCode::RateLimited(retry_after),
+            // refuse requests that need a more privileged role
+            Some(Ok(request)) if Role::required(&request) > role => StatusCode::PermissionDenied,
+            // process request code
+            Some(Ok(request)) => match request {
+                RequestCode::Ping => StatusCode::Ok(BytesMut::new()),
+                RequestCode::GetStats => {
+                    // should response be local stats instead of global stats?
+                    let stats = stats.lock().await;
+                    let mut buffer = BytesMut::with_capacity(9);
+
+                    // don't forget to include this received packet
+                    let (received, _) = stream.codec().get_stats();
+
+                    // total packet bytes received and sent
+                    buffer.put_u32((stats.received + received) as u32);
+                    buffer.put_u32(stats.sent as u32); // big-endian order
+
+                    // total payload bytes before and after compression
+                    buffer.put_u8(stats.ratio());
+
+                    // should the response bytes about to be sent be counted?
+                    StatusCode::Ok(buffer)
+                }
+                RequestCode::ResetStats => {
+                    stats.lock().await.reset();
+                    stream.codec_mut().reset_stats();
+                    compressor.reset_stats();
+
+                    // should the response bytes about to be sent be ignored?
+                    StatusCode::Ok(BytesMut::new())
+                }
+                RequestCode::Compress(_) if !config.algorithms.contains(&Algorithm::Prefix) => {
+                    StatusCode::UnsupportedRequestType
+                }
+                RequestCode::Compress(payload) => match compressor.compress(payload) {
+                    Ok(compressed) => StatusCode::Ok(compressed),
+                    Err(error) => error,
+                },
+                RequestCode::GetHistograms => {
+                    StatusCode::Ok(stats.lock().await.encode_histograms())
+                }
+                RequestCode::Authenticate(_) if !keys.is_enabled() => {
+                    StatusCode::UnsupportedRequestType
+                }
+                RequestCode::Authenticate(signature) if signature.is_empty() => {
+                    let new_challenge = Keys::challenge();
+                    challenge = Some(new_challenge.clone());
+                    StatusCode::Ok(new_challenge)
+                }
+                RequestCode::Authenticate(signature) => {
+                    // a challenge can only be signed once
+                    match challenge.take().and_then(|c| keys.verify(&c, &signature)) {
+                        Some(verified) => {
+                            role = verified;
+                            let mut buffer = BytesMut::with_capacity(1);
+                            buffer.put_u8(role.value());
+                            StatusCode::Ok(buffer)
+                        }
+                        None => {
+                            log::warn!(
+                                conn = client.id,
+                                peer = client.name.as_str();
+                                "authentication failed"
+                            );
+                            StatusCode::AuthenticationFailed
+                        }
+                    }
+                }
+            },
+
+            // pass parsing errors back to encoder to be sent as status code packets
+            Some(Err(error)) => {
+                match &error {
+                    StatusCode::IoError(kind) => log::info!(
+                        conn = client.id,
+                        peer = client.name.as_str(),
+                        error = format!("{:?}", kind).as_str();
+                        "read failed"
+                    ),
+                    _ => log::warn!(
+                        conn = client.id,
+                        peer = client.name.as_str(),
+                        status = StatusCode::name(error.value());
+                        "invalid packet"
+                    ),
+                }
+                error
+            }
+            // stream has closed, exit loop
+            None => break,
+        };
+
+        let status = response.value();
+        let response_bytes = match &response {
+            StatusCode::Ok(payload) => payload.len(),
+            _ => 0,
+        };
+        stream.send(response).await?;
+        let latency = start.elapsed().as_micros() as u64;
+
+        log::debug!(
+            conn = client.id,
+            peer = client.name.as_str(),
+            request = RequestCode::name(request),
+            status = StatusCode::name(status),
+            request_bytes = payload,
+            response_bytes = response_bytes,
+            duration_us = latency;
+            "request"
+        );
+
+        stats.lock().await.record(request, status, latency, payload);
+        idle_since = Instant::now();
+    }
+
+    // don't lose the bytes of the last transaction
+    update_stats(stream.codec_mut(), &mut compressor, &stats).await;
+
+    Ok(())
+}
+
+/// Names a client in log messages.
+fn peer_name(peer: Option<IpAddr>) -> String {
+    match peer {
+        Some(peer) => peer.to_string(),
+        None => String::from("unix client"),
+    }
+}
+
+/// An accepted client, as identified in log messages.
+struct Client {
+    id: u64,
+    peer: Option<IpAddr>, // None for unix clients
+    name: String,
+}
+
+impl Client {
+    fn new(peer: Option<IpAddr>) -> Client {
+        Client {
+            id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+            peer,
+            name: peer_name(peer),
+        }
+    }
+}
+
+/// Moves local codec and compressor stats into global stats.
+async fn update_stats(codec: &mut PacketCodec, compressor: &mut Compressor, stats: &Mutex<Stats>) {
+    // get local stats
+    let (received, sent) = codec.get_stats();
+    let (before, after) = compressor.get_stats();
+
+    // update global stats
+    let mut stats = stats.lock().await;
+    stats.received += received;
+    stats.sent += sent;
+    stats.before += before;
+    stats.after += after;
+
+    // reset local stats
+    codec.reset_stats();
+    compressor.reset_stats();
+}
+
+/// Returns how long to wait for the next packet before checking for expired timeouts,
+/// or None to wait forever.
+fn read_timeout(codec: &PacketCodec, idle_since: Instant, config: &Config) -> Option<Duration> {
+    // a timeout of 0 seconds waits forever
+    let packet = Some(config.packet_timeout())
+        .filter(|&secs| secs > 0)
+        .map(Duration::from_secs);
+    let idle = Some(config.idle_timeout)
+        .filter(|&secs| secs > 0)
+        .map(Duration::from_secs);
+
+    match codec.packet_started() {
+        Some(started) => packet.map(|packet| packet.saturating_sub(started.elapsed())),
+        None => {
+            // a packet may start while we're waiting, so wake up at least once
+            // per packet timeout to notice if it stalls
+            let idle = idle.map(|idle| idle.saturating_sub(idle_since.elapsed()));
+            match (idle, packet) {
+                (Some(idle), Some(packet)) => Some(idle.min(packet)),
+                (idle, packet) => idle.or(packet),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::shutdown::{self, Trigger};
+    use super::*;
+    use crate::client;
+    use crate::packet::ClientCodec;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpStream;
+    use tokio::task::JoinHandle;
+
+    /// A server accepting clients on an ephemeral port.
+    struct Running {
+        addr: String,
+        stats: Arc<Mutex<Stats>>,
+        trigger: Trigger,
+        accepting: JoinHandle<io::Result<()>>,
+        config: watch::Sender<Arc<Config>>, // broadcast to reload
+    }
+
+    async fn start(config: Config) -> Running {
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let listener = Listener::bind(&addr, None).await.unwrap();
+        let addr = listener.local_addr();
+
+        let stats = Arc::new(Mutex::new(Stats::new()));
+        let (config_tx, config_rx) = watch::channel(Arc::new(config));
+        let (trigger, shutdown) = shutdown::channel();
+        let server = Server::new(stats.clone(), config_rx);
+        let accepting = tokio::spawn(server.serve(listener, shutdown));
+
+        Running {
+            addr,
+            stats,
+            trigger,
+            accepting,
+            config: config_tx,
+        }
+    }
+
+    /// Connects a client that sends raw bytes and decodes responses.
+    async fn connect(server: &Running) -> Framed<TcpStream, ClientCodec> {
+        let socket = TcpStream::connect(&server.addr).await.unwrap();
+        Framed::new(socket, ClientCodec::new())
+    }
+
+    #[tokio::test]
+    async fn requests() {
+        let server = start(Config::default()).await;
+        let mut client = client::Client::connect(&server.addr, Duration::from_secs(5))
+            .await
+            .unwrap();
+
+        client.ping().await.unwrap();
+        assert_eq!(&client.compress(b"aaabbbbc").await.unwrap()[..], b"3a4bc");
+        let stats = client.get_stats().await.unwrap();
+        assert_eq!(stats.ratio, 62);
+        drop(client);
+
+        assert!(server.trigger.shutdown(Duration::from_secs(5)).await);
+        server.accepting.await.unwrap().unwrap();
+        let stats = server.stats.lock().await;
+        assert_eq!(stats.connections, 0);
+        assert_eq!((stats.before, stats.after), (8, 5));
+    }
+
+    #[tokio::test]
+    async fn reject_when_busy() {
+        let config = Config {
+            max_connections: 1,
+            busy_action: BusyAction::Reject,
+            ..Config::default()
+        };
+        let server = start(config).await;
+        let mut client = client::Client::connect(&server.addr, Duration::from_secs(5))
+            .await
+            .unwrap();
+        client.ping().await.unwrap(); // holds the only slot
+
+        // read the status without sending, the socket is closed right after it
+        let socket = TcpStream::connect(&server.addr).await.unwrap();
+        let mut rejected = Framed::new(socket, ClientCodec::new());
+        assert_eq!(
+            rejected.next().await.unwrap().unwrap(),
+            StatusCode::ServerBusy
+        );
+        assert!(rejected.next().await.is_none());
+        assert_eq!(server.stats.lock().await.rejected, 1);
+
+        // open connections keep working
+        client.ping().await.unwrap();
+
+        // and the slot is free again once the client closes
+        drop(client);
+        loop {
+            let mut client = client::Client::new(&server.addr, Duration::from_secs(5));
+            match client.ping().await {
+                Ok(()) => break,
+                Err(client::Error::Status(StatusCode::ServerBusy)) => {
+                    time::delay_for(Duration::from_millis(10)).await
+                }
+                Err(error) => panic!("ping failed: {}", error),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn timeouts() {
+        let config = Config {
+            packet_timeout: Some(1),
+            idle_timeout: 2,
+            ..Config::default()
+        };
+        let server = start(config).await;
+        let mut stream = connect(&server).await;
+
+        // a stalled packet gets a timeout status code
+        stream
+            .get_mut()
+            .write_all(b"STRY\0\x05\0\x04he")
+            .await
+            .unwrap();
+        assert_eq!(stream.next().await.unwrap().unwrap(), StatusCode::Timeout);
+
+        // then the connection keeps working
+        stream.send(RequestCode::Ping).await.unwrap();
+        assert_eq!(
+            stream.next().await.unwrap().unwrap(),
+            StatusCode::Ok(BytesMut::new())
+        );
+
+        // an idle connection gets a timeout status code, then is closed
+        assert_eq!(stream.next().await.unwrap().unwrap(), StatusCode::Timeout);
+        assert!(stream.next().await.is_none());
+        let stats = server.stats.lock().await;
+        assert_eq!((stats.packet_timeouts, stats.idle_timeouts), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn rate_limit() {
+        let config = Config {
+            rate_limit_requests: 2,
+            ..Config::default()
+        };
+        let server = start(config).await;
+        let mut client = client::Client::connect(&server.addr, Duration::from_secs(5))
+            .await
+            .unwrap();
+
+        // the bucket starts full
+        client.ping().await.unwrap();
+        client.ping().await.unwrap();
+
+        // then requests get a rate limited status code with milliseconds to wait
+        let retry_after = match client.ping().await {
+            Err(client::Error::Status(StatusCode::RateLimited(retry_after))) => retry_after,
+            result => panic!("ping over rate limit was not limited: {:?}", result),
+        };
+        assert!(retry_after > 0 && retry_after <= 500);
+
+        // and work again after waiting
+        time::delay_for(Duration::from_millis(retry_after as u64)).await;
+        client.ping().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn access_rules() {
+        let config = Config {
+            deny: vec!["127.0.0.1/32".parse().unwrap()],
+            ..Config::default()
+        };
+        let server = start(config).await;
+
+        // a denied client is closed without a response
+        let mut stream = connect(&server).await;
+        assert!(stream.next().await.is_none());
+        assert_eq!(server.stats.lock().await.denied, 1);
+
+        // rules are replaced on reload
+        let config = Config {
+            allow: vec!["127.0.0.1/32".parse().unwrap()],
+            ..Config::default()
+        };
+        server.config.broadcast(Arc::new(config)).unwrap();
+        let mut client = client::Client::connect(&server.addr, Duration::from_secs(5))
+            .await
+            .unwrap();
+        client.ping().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn authentication() {
+        let config = Config {
+            user_key: Some(String::from("user secret")),
+            admin_key: Some(String::from("admin secret")),
+            ..Config::default()
+        };
+        let server = start(config).await;
+        let mut client = client::Client::connect(&server.addr, Duration::from_secs(5))
+            .await
+            .unwrap();
+        let denied = |result: Result<_, client::Error>| {
+            matches!(
+                result,
+                Err(client::Error::Status(StatusCode::PermissionDenied))
+            )
+        };
+
+        // anonymous clients can only ping
+        client.ping().await.unwrap();
+        assert!(denied(client.get_stats().await.map(|_| ())));
+
+        // users can get stats but not reset them
+        assert_eq!(client.authenticate("user secret").await.unwrap(), 1);
+        client.get_stats().await.unwrap();
+        assert!(denied(client.reset_stats().await));
+
+        // a wrong key fails
+        assert!(matches!(
+            client.authenticate("wrong secret").await,
+            Err(client::Error::Status(StatusCode::AuthenticationFailed))
+        ));
+
+        // admins can reset stats
+        assert_eq!(client.authenticate("admin secret").await.unwrap(), 2);
+        client.reset_stats().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn reload() {
+        let config = Config {
+            max_payload: 8192,
+            ..Config::default()
+        };
+        let server = start(config).await;
+        let mut client = client::Client::connect(&server.addr, Duration::from_secs(5))
+            .await
+            .unwrap();
+        let payload = [b'a'; 5000];
+        assert_eq!(&client.compress(&payload).await.unwrap()[..], b"5000a");
+
+        // open connections pick up a smaller max payload
+        let config = Config {
+            max_payload: 4096,
+            ..Config::default()
+        };
+        server.config.broadcast(Arc::new(config)).unwrap();
+        client.ping().await.unwrap(); // the config is read again after each request
+        assert!(matches!(
+            client.compress(&payload).await,
+            Err(client::Error::Status(StatusCode::MessageTooLarge))
+        ));
+    }
+
+    #[tokio::test]
+    async fn shutdown_finishes_request() {
+        let server = start(Config::default()).await;
+        let mut socket = TcpStream::connect(&server.addr).await.unwrap();
+
+        // start a Ping packet and give the server time to decode the header
+        socket.write_all(b"STRY\x00\x00").await.unwrap();
+        time::delay_for(Duration::from_millis(100)).await;
+
+        let draining = tokio::spawn(server.trigger.shutdown(Duration::from_secs(5)));
+        server.accepting.await.unwrap().unwrap();
+        assert!(TcpStream::connect(&server.addr).await.is_err());
+
+        // the packet in progress is answered, then the connection is closed
+        socket.write_all(b"\x00\x01").await.unwrap();
+        let mut stream = Framed::new(socket, ClientCodec::new());
+        assert_eq!(
+            stream.next().await.unwrap().unwrap(),
+            StatusCode::Ok(BytesMut::new())
+        );
+        assert!(stream.next().await.is_none());
+        assert!(draining.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn capture_and_replay() {
+        let dir =
+            std::env::temp_dir().join(format!("compression-service-replay-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = Config {
+            capture_dir: Some(dir.clone()),
+            ..Config::default()
+        };
+        let server = start(config).await;
+
+        let mut client = client::Client::connect(&server.addr, Duration::from_secs(5))
+            .await
+            .unwrap();
+        client.ping().await.unwrap();
+        client.compress(b"aaabbbbc").await.unwrap();
+        assert!(client.compress(b"ABC").await.is_err());
+        drop(client);
+        assert!(server.trigger.shutdown(Duration::from_secs(5)).await);
+
+        // the capture is written once the connection closes
+        let path = std::fs::read_dir(&dir)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        let records = loop {
+            match capture::load(&path) {
+                Ok((_, records)) if records.len() >= 6 => break records,
+                _ => time::delay_for(Duration::from_millis(10)).await,
+            }
+        };
+
+        let config = Arc::new(Config::default());
+        let (recorded, replayed) = replay::replay(&records, config).await.unwrap();
+        assert_eq!(recorded.len(), 3);
+        assert_eq!(recorded[2], StatusCode::NonLowerCase);
+        assert_eq!(recorded, replayed);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,194 @@
+use super::shutdown::Shutdown;
+use super::stats::Stats;
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::{task, time};
+
+/// Serializes stats for the stats file. Call with the stats locked, then write the
+/// result with save once they're unlocked.
+pub fn serialize(stats: &Stats) -> io::Result<Vec<u8>> {
+    serde_json::to_vec(stats).map_err(io::Error::from)
+}
+
+/// Writes serialized stats to a temporary file next to path, then renames it over
+/// path. Readers see either the old or the new stats, never a partial write.
+pub fn save(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let temporary = temporary_path(path);
+    let result = (|| {
+        let mut file = File::create(&temporary)?;
+        file.write_all(contents)?;
+        file.sync_all()?; // make sure the contents are on disk before the rename is
+        fs::rename(&temporary, path)
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+    result
+}
+
+/// Reads stats written by save. Returns None when there is no stats file yet.
+pub fn load(path: &Path) -> io::Result<Option<Stats>> {
+    let contents = match fs::read(path) {
+        Ok(contents) => contents,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(error),
+    };
+    let stats: Stats = serde_json::from_slice(&contents)?;
+
+    // Stats::record adds both histograms for a request, and GetHistograms expects them
+    if !stats.latency.keys().eq(stats.payload.keys()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "latency and payload histograms are for different requests",
+        ));
+    }
+    Ok(Some(stats))
+}
+
+/// Moves an unreadable stats file out of the way, so it isn't overwritten by the
+/// next save and can be looked at later. Returns where it was moved to.
+pub fn set_aside(path: &Path) -> io::Result<PathBuf> {
+    let mut corrupt = path.to_path_buf().into_os_string();
+    corrupt.push(".corrupt");
+    let corrupt = PathBuf::from(corrupt);
+    fs::rename(path, &corrupt)?;
+    Ok(corrupt)
+}
+
+/// Reads stats saved by a previous run. A missing stats file starts from zero, and an
+/// unreadable one is moved aside and also starts from zero.
+pub fn restore(path: &Path) -> Stats {
+    match load(path) {
+        Ok(Some(stats)) => {
+            log::info!("restored stats from {}", path.display());
+            stats
+        }
+        Ok(None) => Stats::new(),
+        Err(error) => {
+            let moved = set_aside(path)
+                .map(|moved| moved.display().to_string())
+                .unwrap_or_else(|error| error.to_string());
+            log::warn!(
+                path = path.display().to_string().as_str(),
+                error = error.to_string().as_str(),
+                moved = moved.as_str();
+                "unreadable stats file, starting from zero"
+            );
+            Stats::new()
+        }
+    }
+}
+
+/// Saves the global stats to path every interval until a graceful shutdown starts.
+pub async fn save_every(
+    path: PathBuf,
+    interval: Duration,
+    stats: Arc<Mutex<Stats>>,
+    mut shutdown: Shutdown,
+) {
+    let mut ticks = time::interval_at(time::Instant::now() + interval, interval);
+    loop {
+        tokio::select! {
+            _ = ticks.tick() => save_stats(&path, &stats).await,
+            _ = shutdown.wait() => return, // saved one last time once connections close
+        }
+    }
+}
+
+/// Saves the global stats to path, logging any error.
+pub async fn save_stats(path: &Path, stats: &Mutex<Stats>) {
+    let result = match serialize(&*stats.lock().await) {
+        Ok(contents) => {
+            // don't block other tasks on disk writes
+            let path = path.to_path_buf();
+            task::spawn_blocking(move || save(&path, &contents))
+                .await
+                .unwrap_or_else(|error| Err(io::Error::other(error)))
+        }
+        Err(error) => Err(error),
+    };
+
+    if let Err(error) = result {
+        log::error!(
+            path = path.display().to_string().as_str(),
+            error = error.to_string().as_str();
+            "failed to save stats"
+        );
+    }
+}
+
+fn temporary_path(path: &Path) -> PathBuf {
+    let mut temporary = path.to_path_buf().into_os_string();
+    temporary.push(".tmp");
+    PathBuf::from(temporary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!(
+            "compression-service-{}-{}",
+            name,
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = dir("persist");
+        let path = dir.join("stats.json");
+        assert!(load(&path).unwrap().is_none());
+
+        let mut stats = Stats::new();
+        stats.received = 100;
+        stats.connections = 2;
+        stats.record(4, 0, 150, 12);
+        save(&path, &serialize(&stats).unwrap()).unwrap();
+        assert!(!temporary_path(&path).exists());
+
+        // open connections aren't saved
+        let loaded = load(&path).unwrap().unwrap();
+        stats.connections = 0;
+        assert_eq!(loaded, stats);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupt() {
+        let dir = dir("persist-corrupt");
+        let path = dir.join("stats.json");
+        fs::write(&path, "{\"received\": 1").unwrap();
+        assert!(load(&path).is_err());
+
+        let corrupt = set_aside(&path).unwrap();
+        assert!(corrupt.exists());
+        assert!(load(&path).unwrap().is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn mismatched_histograms() {
+        let dir = dir("persist-mismatched");
+        let path = dir.join("stats.json");
+        let mut stats = Stats::new();
+        stats.record(4, 0, 150, 12);
+        stats.payload.clear();
+        fs::write(&path, serialize(&stats).unwrap()).unwrap();
+        assert_eq!(load(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,255 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Token bucket that holds up to one second worth of tokens.
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(rate: f64, now: Instant) -> Bucket {
+        Bucket {
+            tokens: rate, // start full
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, rate: f64, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rate);
+        self.updated = now;
+    }
+
+    /// Returns how long until cost tokens are available. A cost larger than the
+    /// bucket only has to wait for a full bucket, otherwise it could never be taken.
+    fn wait(&self, rate: f64, cost: f64) -> Duration {
+        let missing = cost.min(rate) - self.tokens;
+        if missing <= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(missing / rate)
+        }
+    }
+}
+
+/// Request and payload byte buckets of one peer.
+struct Buckets {
+    requests: Bucket,
+    bytes: Bucket,
+}
+
+/// Limits requests per second and payload bytes per second for each peer address.
+pub struct RateLimiter {
+    state: Mutex<State>,
+}
+
+/// Rates and buckets, locked together so the rates can change while running.
+struct State {
+    requests: f64, // per second, 0 for unlimited
+    bytes: f64,    // per second, 0 for unlimited
+    peers: HashMap<IpAddr, Buckets>,
+    sweep_at: usize, // number of peers that triggers the next sweep for idle ones
+}
+
+impl RateLimiter {
+    /// Peers are forgotten once their buckets refill, but only check for them
+    /// when there are more than this many, or twice as many as the last check kept.
+    const MAX_IDLE_PEERS: usize = 1024;
+
+    pub fn new(requests: u32, bytes: u32) -> RateLimiter {
+        RateLimiter {
+            state: Mutex::new(State {
+                requests: requests as f64,
+                bytes: bytes as f64,
+                peers: HashMap::new(),
+                sweep_at: RateLimiter::MAX_IDLE_PEERS,
+            }),
+        }
+    }
+
+    /// Changes both rates. Buckets that are fuller than the new rate are emptied down
+    /// to it the next time they're checked.
+    pub fn set_rates(&self, requests: u32, bytes: u32) {
+        let mut state = self.state.lock().unwrap();
+        state.requests = requests as f64;
+        state.bytes = bytes as f64;
+    }
+
+    /// Takes one request and payload bytes from the peer's buckets. If either bucket
+    /// doesn't have enough tokens, nothing is taken and the time to wait is returned.
+    pub fn check(&self, peer: IpAddr, payload: usize) -> Result<(), Duration> {
+        self.check_at(peer, payload, Instant::now())
+    }
+
+    fn check_at(&self, peer: IpAddr, payload: usize, now: Instant) -> Result<(), Duration> {
+        let mut state = self.state.lock().unwrap();
+        let (requests, bytes) = (state.requests, state.bytes);
+        if requests <= 0.0 && bytes <= 0.0 {
+            return Ok(()); // disabled
+        }
+
+        // sweeping is O(peers), so wait for the map to double again before the next one
+        if state.peers.len() > state.sweep_at {
+            state.peers.retain(|_, buckets| {
+                buckets.requests.refill(requests, now);
+                buckets.bytes.refill(bytes, now);
+                (requests > 0.0 && buckets.requests.tokens < requests)
+                    || (bytes > 0.0 && buckets.bytes.tokens < bytes)
+            });
+            state.sweep_at = (state.peers.len() * 2).max(RateLimiter::MAX_IDLE_PEERS);
+        }
+
+        let peers = &mut state.peers;
+
+        let buckets = peers.entry(peer).or_insert_with(|| Buckets {
+            requests: Bucket::new(requests, now),
+            bytes: Bucket::new(bytes, now),
+        });
+        buckets.requests.refill(requests, now);
+        buckets.bytes.refill(bytes, now);
+
+        let mut wait = Duration::from_secs(0);
+        if requests > 0.0 {
+            wait = wait.max(buckets.requests.wait(requests, 1.0));
+        }
+        if bytes > 0.0 {
+            wait = wait.max(buckets.bytes.wait(bytes, payload as f64));
+        }
+        if wait > Duration::from_secs(0) {
+            return Err(wait);
+        }
+
+        // a disabled bucket isn't taken from, so it doesn't build up a debt
+        if requests > 0.0 {
+            buckets.requests.tokens -= 1.0;
+        }
+        if bytes > 0.0 {
+            buckets.bytes.tokens -= payload as f64; // may go negative for large payloads
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(last: u8) -> IpAddr {
+        IpAddr::from([192, 168, 0, last])
+    }
+
+    #[test]
+    fn disabled() {
+        let limiter = RateLimiter::new(0, 0);
+        for _ in 0..100 {
+            assert_eq!(limiter.check(peer(1), 1 << 14), Ok(()));
+        }
+    }
+
+    #[test]
+    fn requests_per_second() {
+        let limiter = RateLimiter::new(2, 0);
+        let now = Instant::now();
+        assert_eq!(limiter.check_at(peer(1), 0, now), Ok(()));
+        assert_eq!(limiter.check_at(peer(1), 0, now), Ok(()));
+        assert_eq!(
+            limiter.check_at(peer(1), 0, now),
+            Err(Duration::from_millis(500))
+        );
+
+        // other peers have their own buckets
+        assert_eq!(limiter.check_at(peer(2), 0, now), Ok(()));
+
+        // half a second later there's one more token
+        let later = now + Duration::from_millis(500);
+        assert_eq!(limiter.check_at(peer(1), 0, later), Ok(()));
+        assert!(limiter.check_at(peer(1), 0, later).is_err());
+    }
+
+    #[test]
+    fn bytes_per_second() {
+        let limiter = RateLimiter::new(0, 100);
+        let now = Instant::now();
+        assert_eq!(limiter.check_at(peer(1), 60, now), Ok(()));
+        assert_eq!(
+            limiter.check_at(peer(1), 60, now),
+            Err(Duration::from_millis(200))
+        );
+    }
+
+    #[test]
+    fn set_rates() {
+        let limiter = RateLimiter::new(0, 0);
+        let now = Instant::now();
+        assert_eq!(limiter.check_at(peer(1), 0, now), Ok(()));
+
+        limiter.set_rates(1, 0);
+        assert_eq!(limiter.check_at(peer(1), 0, now), Ok(()));
+        assert_eq!(
+            limiter.check_at(peer(1), 0, now),
+            Err(Duration::from_secs(1))
+        );
+
+        limiter.set_rates(0, 0);
+        assert_eq!(limiter.check_at(peer(1), 0, now), Ok(()));
+    }
+
+    #[test]
+    fn payload_larger_than_bucket() {
+        let limiter = RateLimiter::new(0, 100);
+        let now = Instant::now();
+        assert_eq!(limiter.check_at(peer(1), 150, now), Ok(()));
+
+        // the bucket is in debt, and has to refill completely before the next one
+        assert_eq!(
+            limiter.check_at(peer(1), 150, now),
+            Err(Duration::from_millis(1500))
+        );
+    }
+
+    #[test]
+    fn sweep_idle_peers() {
+        let limiter = RateLimiter::new(1, 0);
+        let now = Instant::now();
+        let peers = |limiter: &RateLimiter| limiter.state.lock().unwrap().peers.len();
+        let peer = |index: usize| IpAddr::from([10, 0, (index >> 8) as u8, index as u8]);
+
+        // busy peers are kept by a sweep, then the map has to double before another
+        for index in 0..=RateLimiter::MAX_IDLE_PEERS + 1 {
+            assert_eq!(limiter.check_at(peer(index), 0, now), Ok(()));
+        }
+        assert_eq!(peers(&limiter), RateLimiter::MAX_IDLE_PEERS + 2);
+        let sweep_at = limiter.state.lock().unwrap().sweep_at;
+        assert_eq!(sweep_at, 2 * (RateLimiter::MAX_IDLE_PEERS + 1));
+
+        // once their buckets refill, the next sweep forgets them but not the newer peers
+        let later = now + Duration::from_secs(1);
+        for index in RateLimiter::MAX_IDLE_PEERS + 2..=sweep_at {
+            assert_eq!(limiter.check_at(peer(index), 0, later), Ok(()));
+        }
+        assert_eq!(peers(&limiter), sweep_at + 1);
+        assert_eq!(limiter.check_at(peer(sweep_at + 1), 0, later), Ok(()));
+        assert_eq!(peers(&limiter), sweep_at - RateLimiter::MAX_IDLE_PEERS);
+    }
+
+    #[test]
+    fn sweep_without_bytes_limit() {
+        let limiter = RateLimiter::new(1, 0);
+        let now = Instant::now();
+        let peer = |index: usize| IpAddr::from([10, 0, (index >> 8) as u8, index as u8]);
+
+        // Compress payloads don't count against a disabled bytes limit
+        for index in 0..=RateLimiter::MAX_IDLE_PEERS {
+            assert_eq!(limiter.check_at(peer(index), 100, now), Ok(()));
+        }
+
+        // so the peers are idle once their requests bucket refills
+        let later = now + Duration::from_secs(1);
+        let last = RateLimiter::MAX_IDLE_PEERS + 1;
+        assert_eq!(limiter.check_at(peer(last), 100, later), Ok(()));
+        assert_eq!(limiter.state.lock().unwrap().peers.len(), 1);
+    }
+}
@@ -0,0 +1,189 @@
+use super::capture::{self, Direction, Record};
+use super::config::Config;
+use super::shutdown;
+use super::stats::Stats;
+use super::Server;
+use crate::message::StatusCode;
+use crate::packet::ClientCodec;
+
+use bytes::BytesMut;
+use futures::future;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+use tokio::sync::{watch, Mutex};
+use tokio_util::codec::Decoder;
+
+/// Exit code when the capture file can't be opened or read, like compression-client.
+const EXIT_NO_INPUT: i32 = 66;
+
+/// Exit code when the capture file isn't a valid capture.
+const EXIT_DATA_ERROR: i32 = 65;
+
+/// Replays a capture file, prints the responses that differ from the recorded ones, and
+/// returns the exit code: 0 when every response matches, and 1 when any differs.
+pub async fn run(path: &Path, config: Arc<Config>) -> i32 {
+    let (header, records) = match capture::load(path) {
+        Ok(capture) => capture,
+        Err(error) => {
+            eprintln!("error: {}: {}", path.display(), error);
+            return match error.kind() {
+                // a line that isn't a header or record, or ends mid JSON value
+                io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => EXIT_DATA_ERROR,
+                _ => EXIT_NO_INPUT,
+            };
+        }
+    };
+    // the records were read, so only their bytes can fail to replay or decode
+    let (recorded, replayed) = match replay(&records, config).await {
+        Ok(replayed) => replayed,
+        Err(error) => {
+            eprintln!("error: {}: {}", path.display(), error);
+            return EXIT_DATA_ERROR;
+        }
+    };
+
+    println!(
+        "connection {} from {}, opened at {} ms",
+        header.conn, header.peer, header.opened_ms
+    );
+    let (report, differ) = diff(&recorded, &replayed);
+    print!("{}", report);
+    if differ > 0 {
+        1
+    } else {
+        0
+    }
+}
+
+/// Sends the bytes a client sent in a capture to a fresh connection of a new server, as
+/// fast as it reads them. Returns the responses that were recorded and the ones sent this
+/// time, in order.
+pub async fn replay(
+    records: &[Record],
+    config: Arc<Config>,
+) -> io::Result<(Vec<StatusCode>, Vec<StatusCode>)> {
+    let recorded: Vec<u8> = records
+        .iter()
+        .filter(|record| record.direction == Direction::Out)
+        .flat_map(|record| record.data.iter().copied())
+        .collect();
+
+    // a replay is never reloaded or shut down
+    let (_, config) = watch::channel(config);
+    let (_, shutdown) = shutdown::channel();
+    let server = Server::new(Arc::new(Mutex::new(Stats::new())), config);
+
+    let (socket, client) = UnixStream::pair()?;
+    let (mut reader, mut writer) = tokio::io::split(client);
+    let send = async {
+        for record in records {
+            if record.direction == Direction::In {
+                writer.write_all(&record.data).await?;
+            }
+        }
+        writer.shutdown().await
+    };
+    let receive = async {
+        let mut replayed = Vec::new();
+        reader.read_to_end(&mut replayed).await.map(|_| replayed)
+    };
+
+    // the server may close before every byte is sent, like it did when recorded
+    let (handled, _, replayed) =
+        future::join3(server.handle(socket, shutdown), send, receive).await;
+    handled?;
+    Ok((decode(&recorded)?, decode(&replayed?)?))
+}
+
+/// Decodes every complete response packet, ignoring a partial one at the end.
+fn decode(bytes: &[u8]) -> io::Result<Vec<StatusCode>> {
+    let mut codec = ClientCodec::new();
+    let mut buffer = BytesMut::from(bytes);
+    let mut responses = Vec::new();
+    while let Some(response) = codec.decode(&mut buffer)? {
+        responses.push(response);
+    }
+    Ok(responses)
+}
+
+/// Describes each response that differs, and returns how many did.
+pub fn diff(recorded: &[StatusCode], replayed: &[StatusCode]) -> (String, usize) {
+    let describe = |response: Option<&StatusCode>| match response {
+        Some(response) => format!("{:?}", response),
+        None => String::from("none"),
+    };
+
+    let total = recorded.len().max(replayed.len());
+    let mut report = String::new();
+    let mut differ = 0;
+    for index in 0..total {
+        let (before, after) = (recorded.get(index), replayed.get(index));
+        if before != after {
+            differ += 1;
+            report.push_str(&format!(
+                "response {} differs\n  recorded: {}\n  replayed: {}\n",
+                index + 1,
+                describe(before),
+                describe(after)
+            ));
+        }
+    }
+
+    report.push_str(&format!("{} responses, {} differ\n", total, differ));
+    (report, differ)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(direction: Direction, data: &[u8]) -> Record {
+        Record {
+            time_us: 0,
+            direction,
+            data: data.to_vec(),
+        }
+    }
+
+    #[tokio::test]
+    async fn differences() {
+        // a Ping and a Compress split across reads, then an unknown request code
+        let records = vec![
+            record(Direction::In, b"STRY\x00\x00\x00\x01STRY\x00\x06"),
+            record(Direction::Out, b"STRY\x00\x00\x00\x00"),
+            record(Direction::In, b"\x00\x04aaabbbSTRY\x00\x00\x00\x09"),
+            record(Direction::Out, b"STRY\x00\x04\x00\x003a3b"),
+        ];
+        let (recorded, replayed) = replay(&records, Arc::new(Config::default())).await.unwrap();
+        assert_eq!(recorded.len(), 2);
+        assert_eq!(&recorded[..], &replayed[..2]);
+
+        // the recorded server closed before answering the unknown request
+        let (report, differ) = diff(&recorded, &replayed);
+        assert_eq!(differ, 1);
+        assert_eq!(
+            report,
+            "response 3 differs\n  recorded: none\n  replayed: UnsupportedRequestType\n\
+             3 responses, 1 differ\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn exit_codes() {
+        let path = std::env::temp_dir().join(format!(
+            "compression-service-replay-{}.jsonl",
+            std::process::id()
+        ));
+        let config = Arc::new(Config::default());
+        assert_eq!(run(&path, config.clone()).await, EXIT_NO_INPUT);
+
+        std::fs::write(&path, "{\"conn\": 1").unwrap();
+        assert_eq!(run(&path, config.clone()).await, EXIT_DATA_ERROR);
+        std::fs::write(&path, "not a capture\n").unwrap();
+        assert_eq!(run(&path, config).await, EXIT_DATA_ERROR);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,98 @@
+use futures::future;
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use tokio::time;
+
+/// Tells a task when a graceful shutdown has started. Every clone must be dropped
+/// before the shutdown is finished, so tasks hold one until they're done.
+#[derive(Clone)]
+pub struct Shutdown {
+    started: watch::Receiver<bool>,
+    _finished: mpsc::Sender<()>, // never sent on, only dropped
+}
+
+/// Starts a graceful shutdown and waits for every Shutdown to be dropped.
+pub struct Trigger {
+    started: watch::Sender<bool>,
+    finished: mpsc::Receiver<()>,
+}
+
+pub fn channel() -> (Trigger, Shutdown) {
+    let (started_tx, started_rx) = watch::channel(false);
+    let (finished_tx, finished_rx) = mpsc::channel(1);
+
+    let trigger = Trigger {
+        started: started_tx,
+        finished: finished_rx,
+    };
+    let shutdown = Shutdown {
+        started: started_rx,
+        _finished: finished_tx,
+    };
+
+    (trigger, shutdown)
+}
+
+impl Shutdown {
+    pub fn is_started(&self) -> bool {
+        *self.started.borrow()
+    }
+
+    /// Completes when a graceful shutdown starts, so never once the trigger is dropped
+    /// without starting one.
+    pub async fn wait(&mut self) {
+        while !self.is_started() {
+            // the first recv returns the current value right away, so check again
+            if self.started.recv().await.is_none() {
+                future::pending::<()>().await;
+            }
+        }
+    }
+}
+
+impl Trigger {
+    /// Starts a graceful shutdown, then waits up to deadline for every Shutdown to be
+    /// dropped. Returns false if the deadline passed first.
+    pub async fn shutdown(mut self, deadline: Duration) -> bool {
+        let _ = self.started.broadcast(true);
+
+        // recv only returns None once every sender has been dropped
+        time::timeout(deadline, self.finished.recv()).await.is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn drained() {
+        let (trigger, shutdown) = channel();
+        let mut task = shutdown.clone();
+        drop(shutdown);
+
+        let handle = tokio::spawn(async move {
+            task.wait().await;
+            assert!(task.is_started());
+        });
+
+        assert!(trigger.shutdown(Duration::from_secs(1)).await);
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn trigger_dropped() {
+        let (trigger, mut shutdown) = channel();
+        drop(trigger);
+        let wait = time::timeout(Duration::from_millis(10), shutdown.wait());
+        assert!(wait.await.is_err());
+        assert!(!shutdown.is_started());
+    }
+
+    #[tokio::test]
+    async fn deadline() {
+        let (trigger, shutdown) = channel();
+        assert!(!trigger.shutdown(Duration::from_millis(10)).await);
+        assert!(shutdown.is_started());
+    }
+}
@@ -0,0 +1,100 @@
+use super::metrics::Histogram;
+
+use bytes::{BufMut, BytesMut};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Global server stats. Every field but connections is saved to the stats file.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Stats {
+    pub received: usize,
+    pub sent: usize,
+    pub before: usize,
+    pub after: usize,
+    pub statuses: BTreeMap<u16, usize>, // number of responses per status code value
+    #[serde(skip)]
+    pub connections: usize, // currently open client connections
+    pub rejected: usize,                // connections rejected past max connections
+    pub denied: usize,                  // connections denied by access rules
+    pub packet_timeouts: usize,         // partially received packets given up on
+    pub idle_timeouts: usize,           // connections closed for not sending a packet
+    pub latency: BTreeMap<u16, Histogram>, // microseconds per request code value
+    pub payload: BTreeMap<u16, Histogram>, // request payload bytes per request code value
+}
+
+impl Default for Stats {
+    fn default() -> Stats {
+        Stats::new()
+    }
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats {
+            received: 0,
+            sent: 0,
+            before: 0,
+            after: 0,
+            statuses: BTreeMap::new(),
+            connections: 0,
+            rejected: 0,
+            denied: 0,
+            packet_timeouts: 0,
+            idle_timeouts: 0,
+            latency: BTreeMap::new(),
+            payload: BTreeMap::new(),
+        }
+    }
+
+    /// Clears all counters. Open connections are a gauge, not a counter, so they're kept.
+    pub fn reset(&mut self) {
+        self.received = 0;
+        self.sent = 0;
+        self.before = 0;
+        self.after = 0;
+        self.rejected = 0;
+        self.denied = 0;
+        self.packet_timeouts = 0;
+        self.idle_timeouts = 0;
+        self.statuses.clear();
+        self.latency.clear();
+        self.payload.clear();
+    }
+
+    /// Records one request and response transaction. Packets that could not be parsed
+    /// into a request use a request code value of 0.
+    pub fn record(&mut self, request: u16, status: u16, latency: u64, payload: usize) {
+        *self.statuses.entry(status).or_insert(0) += 1;
+        self.latency.entry(request).or_default().record(latency);
+        self.payload
+            .entry(request)
+            .or_default()
+            .record(payload as u64);
+    }
+
+    /// Encodes the latency then payload histogram of every recorded request code value.
+    pub fn encode_histograms(&self) -> BytesMut {
+        let mut buffer =
+            BytesMut::with_capacity(self.latency.len() * (2 + 2 * Histogram::ENCODED_LEN));
+        for (request, latency) in &self.latency {
+            buffer.put_u16(*request); // big-endian order
+            latency.encode(&mut buffer);
+            match self.payload.get(request) {
+                Some(payload) => payload.encode(&mut buffer),
+                None => Histogram::new().encode(&mut buffer), // only from a bad stats file
+            }
+        }
+        buffer
+    }
+
+    /// Payload bytes after compression as a percentage of payload bytes before compression.
+    pub fn ratio(&self) -> u8 {
+        let percent = if self.before == 0 {
+            0.0 // or should the compression ratio be 100%?
+        } else {
+            (self.after as f32) / (self.before as f32) * 100.0
+        };
+        percent as u8
+    }
+}
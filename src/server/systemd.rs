@@ -0,0 +1,133 @@
+use std::env;
+use std::io;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::io::RawFd;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+/// The first file descriptor passed by socket activation, after stdin, stdout, and stderr.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// A listening socket passed by systemd socket activation.
+#[derive(Debug, PartialEq)]
+pub struct ListenFd {
+    pub fd: RawFd,
+    pub name: String, // FileDescriptorName= of the socket unit, "unknown" by default
+}
+
+/// Returns the listening sockets passed by systemd, or an empty list when the server
+/// wasn't socket activated. The variables are removed so they aren't passed on, so call
+/// this before starting any other threads, which could be reading the environment.
+pub fn listen_fds() -> io::Result<Vec<ListenFd>> {
+    let pid = env::var("LISTEN_PID").ok();
+    let fds = env::var("LISTEN_FDS").ok();
+    let names = env::var("LISTEN_FDNAMES").ok();
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    parse_listen_fds(
+        std::process::id(),
+        pid.as_deref(),
+        fds.as_deref(),
+        names.as_deref(),
+    )
+}
+
+fn parse_listen_fds(
+    process: u32,
+    pid: Option<&str>,
+    fds: Option<&str>,
+    names: Option<&str>,
+) -> io::Result<Vec<ListenFd>> {
+    // the variables are meant for another process if the pid doesn't match
+    let (pid, fds) = match (pid, fds) {
+        (Some(pid), Some(fds)) if pid.parse() == Ok(process) => (pid, fds),
+        _ => return Ok(Vec::new()),
+    };
+
+    let count: RawFd = fds.parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("LISTEN_FDS '{}' for pid {} is not a number", fds, pid),
+        )
+    })?;
+
+    let mut names = names.unwrap_or_default().split(':');
+    Ok((LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| ListenFd {
+            fd,
+            name: names
+                .next()
+                .filter(|name| !name.is_empty())
+                .unwrap_or("unknown")
+                .to_string(),
+        })
+        .collect())
+}
+
+/// Returns the socket to send notifications to, or None when the server wasn't started
+/// by systemd with one.
+pub fn notify_socket() -> Option<String> {
+    env::var("NOTIFY_SOCKET")
+        .ok()
+        .filter(|path| !path.is_empty())
+}
+
+/// Sends a state like "READY=1" to systemd's notify socket.
+pub fn notify(socket: &str, state: &str) -> io::Result<()> {
+    // paths starting with @ are in the abstract namespace
+    let addr = match socket.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(socket)?,
+    };
+    UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn not_activated() {
+        assert_eq!(parse_listen_fds(10, None, None, None).unwrap(), vec![]);
+        assert_eq!(
+            parse_listen_fds(10, Some("11"), Some("1"), None).unwrap(),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn activated() {
+        assert_eq!(
+            parse_listen_fds(10, Some("10"), Some("2"), Some("tls:")).unwrap(),
+            vec![
+                ListenFd {
+                    fd: 3,
+                    name: String::from("tls")
+                },
+                ListenFd {
+                    fd: 4,
+                    name: String::from("unknown")
+                }
+            ]
+        );
+        assert!(parse_listen_fds(10, Some("10"), Some("two"), None).is_err());
+    }
+
+    #[test]
+    fn notify_socket() {
+        let path =
+            env::temp_dir().join(format!("compression-service-notify-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+
+        notify(path.to_str().unwrap(), "READY=1").unwrap();
+        assert!(notify("/nonexistent/notify", "STOPPING=1").is_err());
+
+        let mut buffer = [0; 16];
+        let len = socket.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], b"READY=1");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,98 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{
+    AllowAnyAuthenticatedClient, NoClientAuth, PrivateKey, RootCertStore, ServerConfig,
+};
+use tokio_rustls::TlsAcceptor;
+
+/// Builds a TLS acceptor from PEM certificate chain and private key files. When
+/// client_ca is set, clients must present a certificate signed by one of its certificates.
+pub fn acceptor(cert: &Path, key: &Path, client_ca: Option<&Path>) -> io::Result<TlsAcceptor> {
+    let verifier = match client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            let (added, _) = roots
+                .add_pem_file(&mut open(path)?)
+                .map_err(|_| invalid(path, "unreadable certificates"))?;
+            if added == 0 {
+                return Err(invalid(path, "no certificates"));
+            }
+            AllowAnyAuthenticatedClient::new(roots)
+        }
+        None => NoClientAuth::new(),
+    };
+
+    let certs =
+        pemfile::certs(&mut open(cert)?).map_err(|_| invalid(cert, "unreadable certificates"))?;
+    if certs.is_empty() {
+        return Err(invalid(cert, "no certificates"));
+    }
+
+    let mut config = ServerConfig::new(verifier);
+    config
+        .set_single_cert(certs, private_key(key)?)
+        .map_err(|error| invalid(key, &error.to_string()))?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Reads the first PKCS #8 or RSA private key in a PEM file.
+fn private_key(path: &Path) -> io::Result<PrivateKey> {
+    let mut keys = pemfile::pkcs8_private_keys(&mut open(path)?)
+        .map_err(|_| invalid(path, "unreadable private key"))?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut open(path)?)
+            .map_err(|_| invalid(path, "unreadable private key"))?;
+    }
+    keys.into_iter()
+        .next()
+        .ok_or_else(|| invalid(path, "no private key"))
+}
+
+fn open(path: &Path) -> io::Result<BufReader<File>> {
+    let file = File::open(path)
+        .map_err(|error| io::Error::new(error.kind(), format!("{}: {}", path.display(), error)))?;
+    Ok(BufReader::new(file))
+}
+
+fn invalid(path: &Path, reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {}", path.display(), reason),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs};
+
+    #[test]
+    fn self_signed() {
+        let cert = rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+        let dir = env::temp_dir().join(format!(
+            "compression-service-tls-unit-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("cert.pem"), cert.serialize_pem().unwrap()).unwrap();
+        fs::write(dir.join("key.pem"), cert.serialize_private_key_pem()).unwrap();
+
+        assert!(acceptor(&dir.join("cert.pem"), &dir.join("key.pem"), None).is_ok());
+        assert!(acceptor(
+            &dir.join("cert.pem"),
+            &dir.join("key.pem"),
+            Some(&dir.join("cert.pem"))
+        )
+        .is_ok());
+
+        // a certificate file is not a key file
+        assert!(acceptor(&dir.join("cert.pem"), &dir.join("cert.pem"), None).is_err());
+        assert!(acceptor(&dir.join("missing.pem"), &dir.join("key.pem"), None).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,13 +1,17 @@
 use bytes::{BufMut, BytesMut};
+use std::env;
 use std::error::Error;
-use std::io::prelude::*;
+use std::fs;
+use std::io::{self, prelude::*};
 use std::net::TcpStream;
-use std::process::Command;
+use std::ops::{Deref, DerefMut};
+use std::os::unix::net::UnixStream;
+use std::process::{Child, Command, Stdio};
 use std::thread;
 use std::time::Duration;
 
-fn transceive_packet(
-    stream: &mut TcpStream,
+fn transceive_packet<S: Read + Write>(
+    stream: &mut S,
     request: u16,
     payload: &[u8],
     response: &mut [u8],
@@ -22,13 +26,68 @@ fn transceive_packet(
     Ok(())
 }
 
+/// A spawned server, killed when dropped so a failed test doesn't leave it running.
+struct Server(Child);
+
+impl Server {
+    fn spawn(command: &mut Command) -> io::Result<Server> {
+        command.spawn().map(Server)
+    }
+}
+
+impl Deref for Server {
+    type Target = Child;
+
+    fn deref(&self) -> &Child {
+        &self.0
+    }
+}
+
+impl DerefMut for Server {
+    fn deref_mut(&mut self) -> &mut Child {
+        &mut self.0
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        // it may have exited already
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Returns a port that was free a moment ago, for a spawned server to listen on.
+fn free_port() -> u16 {
+    let listener = std::net::TcpListener::bind("[::1]:0").unwrap();
+    listener.local_addr().unwrap().port()
+}
+
+/// Connects to a server that was just spawned, retrying until it's listening.
+fn connect(addr: &str) -> io::Result<TcpStream> {
+    for _ in 0..100 {
+        match TcpStream::connect(addr) {
+            Err(error) if error.kind() == io::ErrorKind::ConnectionRefused => {
+                thread::sleep(Duration::from_millis(50))
+            }
+            result => return result,
+        }
+    }
+    TcpStream::connect(addr)
+}
+
 #[test]
 fn integration_tests() -> Result<(), Box<dyn Error>> {
     // use only one integration test so that we can run the following
     // sequentially, keep the server alive, and generate some stats
-    let mut server = Command::new("cargo").arg("run").spawn()?;
-    thread::sleep(Duration::from_secs(1)); // wait for server to start
-    let mut stream = TcpStream::connect("::1:4000")?;
+    let socket = env::temp_dir().join(format!("compression-service-{}.sock", std::process::id()));
+    let port = free_port();
+    let mut server = Server::spawn(
+        Command::new(env!("CARGO_BIN_EXE_compression-service"))
+            .args(["--listen", &format!("[::]:{}", port), "--listen"])
+            .arg(format!("unix:{}", socket.display())),
+    )?;
+    let mut stream = connect(&format!("[::1]:{}", port))?;
 
     // test good packets
 
@@ -50,13 +109,13 @@ fn integration_tests() -> Result<(), Box<dyn Error>> {
     // compress "aaa"
     let mut response = [0; 10];
     transceive_packet(&mut stream, 4, "aaa".as_bytes(), &mut response)?;
-    assert_eq!(&response, b"STRY\0\x02\0\03a", "compress 'aaa' failed");
+    assert_eq!(&response, b"STRY\0\x02\0\x003a", "compress 'aaa' failed");
 
     // compress "aaaaabbb"
     let mut response = [0; 12];
     transceive_packet(&mut stream, 4, "aaaaabbb".as_bytes(), &mut response)?;
     assert_eq!(
-        &response, b"STRY\0\x04\0\05a3b",
+        &response, b"STRY\0\x04\0\x005a3b",
         "compress 'aaaaabbb' failed"
     );
 
@@ -64,7 +123,7 @@ fn integration_tests() -> Result<(), Box<dyn Error>> {
     let mut response = [0; 16];
     transceive_packet(&mut stream, 4, "aaaaabbbbbbaaabb".as_bytes(), &mut response)?;
     assert_eq!(
-        &response, b"STRY\0\x08\0\05a6b3abb",
+        &response, b"STRY\0\x08\0\x005a6b3abb",
         "compress 'aaaaabbbbbbaaabb' failed"
     );
 
@@ -80,7 +139,7 @@ fn integration_tests() -> Result<(), Box<dyn Error>> {
     let mut response = [0; 17];
     transceive_packet(&mut stream, 4, "aaaccddddhhhhi".as_bytes(), &mut response)?;
     assert_eq!(
-        &response, b"STRY\0\x09\0\03acc4d4hi",
+        &response, b"STRY\0\x09\0\x003acc4d4hi",
         "compress 'aaaccddddhhhhi' failed"
     );
 
@@ -170,6 +229,533 @@ fn integration_tests() -> Result<(), Box<dyn Error>> {
         "I think it's pronounced 'Kyle'"
     );
 
-    server.kill()?;
+    // get histograms, one entry per request type seen since reset stats
+    let mut response = [0; 8];
+    transceive_packet(&mut stream, 5, &[], &mut response)?;
+    assert_eq!(&response[..4], b"STRY", "get histograms failed");
+    assert_eq!(&response[6..], b"\0\0", "get histograms failed");
+    let length = u16::from_be_bytes([response[4], response[5]]) as usize;
+    assert_eq!(length % 234, 0, "get histograms has a partial entry");
+    let mut payload = vec![0; length];
+    stream.read_exact(&mut payload)?;
+    let requests: Vec<u8> = payload.chunks(234).map(|entry| entry[1]).collect();
+    assert_eq!(
+        &requests,
+        &[0, 2, 3, 4],
+        "get histograms missing a request type"
+    );
+
+    // ping over the unix domain socket listener
+    let mut stream = UnixStream::connect(&socket)?;
+    let mut response = [0; 8];
+    transceive_packet(&mut stream, 1, &[], &mut response)?;
+    assert_eq!(&response, b"STRY\0\0\0\0", "unix socket ping failed");
+
+    // graceful shutdown finishes a partially received request
+    stream.write_all(b"STRY\0\x03\0\x04")?;
+    thread::sleep(Duration::from_millis(10));
+    Command::new("kill").arg(server.id().to_string()).status()?; // SIGTERM
+    thread::sleep(Duration::from_millis(100));
+    assert!(server.try_wait()?.is_none(), "server exited mid request");
+    let mut response = [0; 10];
+    stream.write_all(b"aaa")?;
+    stream.read_exact(&mut response)?;
+    assert_eq!(
+        &response, b"STRY\0\x02\0\x003a",
+        "compress during shutdown failed"
+    );
+    assert!(server.wait()?.success(), "server did not exit cleanly");
+    assert!(!socket.exists(), "unix socket file was not removed");
+
+    Ok(())
+}
+
+#[test]
+fn tls() -> Result<(), Box<dyn Error>> {
+    use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa};
+    use rustls::internal::pemfile;
+    use std::sync::Arc;
+
+    // self-signed CA that signs both the server and client certificates
+    let mut params = CertificateParams::new(vec![]);
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca = Certificate::from_params(params)?;
+    let server_cert = Certificate::from_params(CertificateParams::new(vec!["localhost".into()]))?;
+    let client_cert = Certificate::from_params(CertificateParams::new(vec!["client".into()]))?;
+
+    let dir = env::temp_dir().join(format!("compression-service-tls-{}", std::process::id()));
+    fs::create_dir_all(&dir)?;
+    fs::write(dir.join("ca.pem"), ca.serialize_pem()?)?;
+    fs::write(
+        dir.join("cert.pem"),
+        server_cert.serialize_pem_with_signer(&ca)?,
+    )?;
+    fs::write(dir.join("key.pem"), server_cert.serialize_private_key_pem())?;
+
+    let addr = format!("[::1]:{}", free_port());
+    let server = Server::spawn(
+        Command::new(env!("CARGO_BIN_EXE_compression-service"))
+            .args(["--listen", &format!("tls:{}", addr), "--tls-cert"])
+            .arg(dir.join("cert.pem"))
+            .arg("--tls-key")
+            .arg(dir.join("key.pem"))
+            .arg("--tls-client-ca")
+            .arg(dir.join("ca.pem")),
+    )?;
+    drop(connect(&addr)?);
+
+    let connect = |client: Option<&Certificate>| -> Result<_, Box<dyn Error>> {
+        let mut config = rustls::ClientConfig::new();
+        config
+            .root_store
+            .add_pem_file(&mut ca.serialize_pem()?.as_bytes())
+            .map_err(|_| "bad ca certificate")?;
+        if let Some(client) = client {
+            let certs = pemfile::certs(&mut client.serialize_pem_with_signer(&ca)?.as_bytes())
+                .map_err(|_| "bad client certificate")?;
+            let key = rustls::PrivateKey(client.serialize_private_key_der());
+            config.set_single_client_cert(certs, key)?;
+        }
+        let name = webpki::DNSNameRef::try_from_ascii_str("localhost")?;
+        let session = rustls::ClientSession::new(&Arc::new(config), name);
+        Ok(rustls::StreamOwned::new(
+            session,
+            TcpStream::connect(&addr)?,
+        ))
+    };
+
+    // a client with a certificate signed by the CA is accepted
+    let mut stream = connect(Some(&client_cert))?;
+    let mut response = [0; 8];
+    transceive_packet(&mut stream, 1, &[], &mut response)?;
+    assert_eq!(&response, b"STRY\0\0\0\0", "tls ping failed");
+
+    // a client without a certificate is not
+    let mut stream = connect(None)?;
+    assert!(
+        transceive_packet(&mut stream, 1, &[], &mut response).is_err(),
+        "tls client without a certificate was accepted"
+    );
+
+    drop(server);
+    fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn systemd() -> Result<(), Box<dyn Error>> {
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixDatagram;
+    use std::os::unix::process::CommandExt;
+
+    // stand in for systemd with a socket to pass and a socket for notifications
+    let listener = std::net::TcpListener::bind("[::1]:0")?;
+    let addr = listener.local_addr()?;
+    let unused = format!("[::1]:{}", free_port());
+    let notify_path =
+        env::temp_dir().join(format!("compression-service-notify-{}", std::process::id()));
+    let _ = fs::remove_file(&notify_path);
+    let notify = UnixDatagram::bind(&notify_path)?;
+    notify.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    // pass the listener as fd 3, and set LISTEN_PID to the pid of the exec'd server
+    let fd = listener.as_raw_fd();
+    let mut command = Command::new("sh");
+    command
+        .args(["-c", "LISTEN_PID=$$ exec \"$0\" --listen \"$1\""])
+        .arg(env!("CARGO_BIN_EXE_compression-service"))
+        .arg(&unused)
+        .env("LISTEN_FDS", "1")
+        .env("NOTIFY_SOCKET", &notify_path);
+    unsafe {
+        command.pre_exec(move || {
+            // dup2 clears close on exec, unless the fds are the same
+            let result = if fd == 3 {
+                libc::fcntl(fd, libc::F_SETFD, 0)
+            } else {
+                libc::dup2(fd, 3)
+            };
+            if result < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut server = Server::spawn(&mut command)?;
+
+    let mut state = [0; 64];
+    let len = notify.recv(&mut state)?;
+    assert_eq!(&state[..len], b"READY=1", "server did not notify ready");
+
+    // the inherited socket is used instead of the listen option
+    let mut stream = TcpStream::connect(addr)?;
+    let mut response = [0; 8];
+    transceive_packet(&mut stream, 1, &[], &mut response)?;
+    assert_eq!(
+        &response, b"STRY\0\0\0\0",
+        "ping over inherited socket failed"
+    );
+    assert!(TcpStream::connect(&unused).is_err());
+    drop(stream);
+
+    Command::new("kill").arg(server.id().to_string()).status()?; // SIGTERM
+    let len = notify.recv(&mut state)?;
+    assert_eq!(
+        &state[..len],
+        b"STOPPING=1",
+        "server did not notify stopping"
+    );
+    assert!(server.wait()?.success());
+
+    fs::remove_file(&notify_path)?;
+    Ok(())
+}
+
+#[test]
+fn reload_config() -> Result<(), Box<dyn Error>> {
+    let config = env::temp_dir().join(format!(
+        "compression-service-reload-{}.toml",
+        std::process::id()
+    ));
+    let addr = format!("[::1]:{}", free_port());
+    let listen = format!("listen = [\"{}\"]\n", addr);
+    fs::write(&config, format!("{}max_payload = 8192\n", listen))?;
+    let server = Server::spawn(
+        Command::new(env!("CARGO_BIN_EXE_compression-service"))
+            .arg("--config")
+            .arg(&config),
+    )?;
+    let mut stream = connect(&addr)?;
+    let reload = |contents: &str| -> Result<(), Box<dyn Error>> {
+        fs::write(&config, format!("{}{}", listen, contents))?;
+        Command::new("kill")
+            .args(["-HUP", &server.id().to_string()])
+            .status()?;
+        thread::sleep(Duration::from_millis(500)); // wait for reload
+        Ok(())
+    };
+
+    let payload = [b'a'; 5000];
+    let mut response = [0; 13];
+    transceive_packet(&mut stream, 4, &payload, &mut response)?;
+    assert_eq!(&response, b"STRY\0\x05\0\x005000a", "compress failed");
+
+    // open connections pick up a smaller max payload
+    reload("max_payload = 4096\n")?;
+    let mut response = [0; 8];
+    transceive_packet(&mut stream, 4, &payload, &mut response)?;
+    assert_eq!(&response, b"STRY\0\0\0\x02", "max payload was not reloaded");
+
+    // an invalid config is ignored
+    reload("max_payload = 100\n")?;
+    let mut stream = TcpStream::connect(&addr)?;
+    transceive_packet(&mut stream, 1, &[], &mut response)?;
+    assert_eq!(
+        &response, b"STRY\0\0\0\0",
+        "ping after invalid config failed"
+    );
+    transceive_packet(&mut stream, 4, &payload, &mut response)?;
+    assert_eq!(&response, b"STRY\0\0\0\x02", "invalid config was applied");
+
+    drop(server);
+    fs::remove_file(&config)?;
+    Ok(())
+}
+
+#[test]
+fn persist_stats() -> Result<(), Box<dyn Error>> {
+    let dir = env::temp_dir().join(format!("compression-service-stats-{}", std::process::id()));
+    fs::create_dir_all(&dir)?;
+    let path = dir.join("stats.json");
+    let addr = format!("[::1]:{}", free_port());
+    let start = || {
+        Server::spawn(
+            Command::new(env!("CARGO_BIN_EXE_compression-service"))
+                .args(["--listen", &addr, "--stats-interval", "1", "--stats-file"])
+                .arg(&path),
+        )
+    };
+
+    // stats saved by a previous run are restored
+    fs::write(&path, "{\"received\": 1000, \"sent\": 2000}")?;
+    let mut server = start()?;
+    let mut stream = connect(&addr)?;
+    let mut response = [0; 17];
+    transceive_packet(&mut stream, 2, &[], &mut response)?;
+    assert_eq!(
+        &response[..16],
+        b"STRY\0\x09\0\0\0\0\x03\xf0\0\0\x07\xd0",
+        "stats were not restored"
+    );
+
+    // and saved again on shutdown
+    Command::new("kill").arg(server.id().to_string()).status()?; // SIGTERM
+    assert!(server.wait()?.success(), "server did not exit cleanly");
+    let saved: serde_json::Value = serde_json::from_slice(&fs::read(&path)?)?;
+    assert_eq!(saved["received"], 1008, "stats were not saved on shutdown");
+    assert_eq!(saved["sent"], 2017, "stats were not saved on shutdown");
+
+    // a corrupt stats file is moved aside, and stats are saved every interval
+    fs::write(&path, "{\"received\": 1")?;
+    let server = start()?;
+    let mut stream = connect(&addr)?;
+    let mut response = [0; 8];
+    transceive_packet(&mut stream, 1, &[], &mut response)?;
+    assert_eq!(
+        &response, b"STRY\0\0\0\0",
+        "ping after corrupt stats failed"
+    );
+    assert_eq!(
+        fs::read_to_string(dir.join("stats.json.corrupt"))?,
+        "{\"received\": 1",
+        "corrupt stats file was not moved aside"
+    );
+    for _ in 0..50 {
+        if path.exists() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100)); // wait for the first interval
+    }
+    let saved: serde_json::Value = serde_json::from_slice(&fs::read(&path)?)?;
+    assert_eq!(saved["received"], 8, "stats were not saved periodically");
+
+    drop(server);
+    fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn command_line_client() -> Result<(), Box<dyn Error>> {
+    let addr = format!("[::1]:{}", free_port());
+    let server = Server::spawn(
+        Command::new(env!("CARGO_BIN_EXE_compression-service")).args(["--listen", &addr]),
+    )?;
+    drop(connect(&addr)?);
+    let client = |args: &[&str], input: &str| -> Result<(i32, String, String), Box<dyn Error>> {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_compression-client"))
+            .args(["--addr", &addr])
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        child.stdin.take().unwrap().write_all(input.as_bytes())?;
+        let output = child.wait_with_output()?;
+        Ok((
+            output.status.code().unwrap_or(-1),
+            String::from_utf8(output.stdout)?,
+            String::from_utf8(output.stderr)?,
+        ))
+    };
+
+    let (code, stdout, _) = client(&["ping"], "")?;
+    assert_eq!(code, 0, "ping failed");
+    assert!(stdout.starts_with("ok in "), "ping output was {:?}", stdout);
+
+    let (code, stdout, _) = client(&["compress"], "aaaabbb\n")?;
+    assert_eq!((code, stdout.as_str()), (0, "4a3b\n"), "compress failed");
+
+    // status codes are exit codes
+    let (code, stdout, stderr) = client(&["compress"], "ABC")?;
+    assert_eq!(code, 37, "non lowercase compress did not fail");
+    assert_eq!(stdout, "");
+    assert!(
+        stderr.contains("not all lowercase"),
+        "stderr was {:?}",
+        stderr
+    );
+
+    let (code, stdout, _) = client(&["stats", "--json"], "")?;
+    assert_eq!(code, 0, "stats failed");
+    let stats: serde_json::Value = serde_json::from_str(&stdout)?;
+    assert_eq!(stats["ratio"], 57, "stats were {}", stdout);
+
+    let (code, stdout, _) = client(&["reset"], "")?;
+    assert_eq!((code, stdout.as_str()), (0, ""), "reset failed");
+    let (_, stdout, _) = client(&["stats"], "")?;
+    assert!(
+        stdout.ends_with("compression ratio: 0%\n"),
+        "stats were {:?}",
+        stdout
+    );
+
+    drop(server);
+    let (code, _, _) = client(&["ping"], "")?;
+    assert_eq!(code, 69, "ping without a server did not fail");
+    Ok(())
+}
+
+#[test]
+fn offline_compression() -> Result<(), Box<dyn Error>> {
+    let dir = env::temp_dir().join(format!(
+        "compression-service-offline-{}",
+        std::process::id()
+    ));
+    fs::create_dir_all(&dir)?;
+    let client = |args: &[&str], input: &[u8]| -> Result<(i32, Vec<u8>, String), Box<dyn Error>> {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_compression-client"))
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        child.stdin.take().unwrap().write_all(input)?;
+        let output = child.wait_with_output()?;
+        Ok((
+            output.status.code().unwrap_or(-1),
+            output.stdout,
+            String::from_utf8(output.stderr)?,
+        ))
+    };
+
+    // stdin works without a server
+    let (code, stdout, stderr) = client(&["compress", "--offline"], b"aaaabbb\n")?;
+    assert_eq!(
+        (code, stdout.as_slice()),
+        (0, &b"4a3b\n"[..]),
+        "compress failed"
+    );
+    assert_eq!(stderr, "compression ratio: 57%\n");
+
+    // inputs longer than a payload are split into chunks, without splitting short runs
+    let mut input = Vec::new();
+    for letter in b"abcdefghij".iter().cycle().take(5000) {
+        input.extend_from_slice(&[*letter; 5]);
+    }
+    fs::write(dir.join("input"), &input)?;
+    let input_path = dir.join("input").display().to_string();
+    let (code, compressed, stderr) = client(&["compress", "--offline", &input_path], b"")?;
+    assert_eq!(code, 0, "compress file failed");
+    assert_eq!(compressed, b"5a5b5c5d5e5f5g5h5i5j".repeat(500));
+    assert_eq!(stderr, "compression ratio: 40%\n");
+
+    let (code, decompressed, _) = client(&["decompress"], &compressed)?;
+    assert_eq!(code, 0, "decompress failed");
+    assert!(decompressed == input, "decompress did not reverse compress");
+
+    // invalid input exits with the status code value
+    let (code, _, stderr) = client(&["decompress"], b"3a4")?;
+    assert_eq!(code, 36, "invalid decompress did not fail");
+    assert!(stderr.contains("non_alphabetic"), "stderr was {:?}", stderr);
+
+    // a number too large to decompress fails instead of running out of memory
+    let (code, _, _) = client(&["decompress"], b"99999999999a")?;
+    assert_eq!(code, 2, "huge decompress did not fail");
+
+    fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn benchmark() -> Result<(), Box<dyn Error>> {
+    let addr = format!("[::1]:{}", free_port());
+    let server = Server::spawn(
+        Command::new(env!("CARGO_BIN_EXE_compression-service")).args(["--listen", &addr]),
+    )?;
+    drop(connect(&addr)?);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_compression-bench"))
+        .args(["--addr", &addr, "--connections", "4", "--duration", "1"])
+        .output()?;
+    drop(server);
+
+    assert!(output.status.success(), "benchmark failed");
+    let report = String::from_utf8(output.stdout)?;
+    let requests: usize = report
+        .strip_prefix("requests: ")
+        .and_then(|rest| rest.split(' ').next())
+        .ok_or("no request count")?
+        .parse()?;
+    assert!(requests > 0, "no requests were sent");
+    assert!(
+        report.contains("\nlatency: p50 "),
+        "report was {:?}",
+        report
+    );
+    assert!(report.ends_with("\nerrors: 0\n"), "report was {:?}", report);
+    Ok(())
+}
+
+#[test]
+fn conformance() -> Result<(), Box<dyn Error>> {
+    let config = env::temp_dir().join(format!(
+        "compression-service-conformance-{}.toml",
+        std::process::id()
+    ));
+    let addr = format!("[::1]:{}", free_port());
+    fs::write(
+        &config,
+        format!(
+            "listen = [\"{}\"]\n\
+             admin_key = \"admin secret\"\n\
+             user_key = \"user secret\"\n\
+             packet_timeout = 1\n\
+             max_connections = 4\n\
+             busy_action = \"reject\"\n\
+             rate_limit_requests = 1000\n",
+            addr
+        ),
+    )?;
+    let server = Server::spawn(
+        Command::new(env!("CARGO_BIN_EXE_compression-service"))
+            .arg("--config")
+            .arg(&config),
+    )?;
+    drop(connect(&addr)?);
+
+    let conformance = |key: &str, args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_compression-conformance"))
+            .args(["--addr", &addr])
+            .args(args)
+            .env("COMPRESSION_SERVICE_KEY", key)
+            .output()
+    };
+    let output = conformance(
+        "admin secret",
+        &[
+            "--user-key",
+            "user secret",
+            "--packet-timeout",
+            "1",
+            "--max-connections",
+            "4",
+            "--rate-limit",
+            "1000",
+        ],
+    )?;
+    let report = String::from_utf8(output.stdout)?;
+    assert!(output.status.success(), "conformance failed: {}", report);
+    assert!(
+        report.ends_with(
+            "\nstatus codes not exercised: 1 unknown_error\n28 passed, 0 failed, 0 skipped\n"
+        ),
+        "report was {}",
+        report
+    );
+
+    // checks that need to know the server's config are skipped without it
+    let output = conformance("admin secret", &["--no-reset"])?;
+    let report = String::from_utf8(output.stdout)?;
+    assert!(output.status.success(), "conformance failed: {}", report);
+    assert!(
+        report.contains("SKIP timeout: needs --packet-timeout\n")
+            && report.contains("38 server_busy (skipped)")
+            && report.ends_with("\n23 passed, 0 failed, 5 skipped\n"),
+        "report was {}",
+        report
+    );
+
+    // every check after authenticating fails with the wrong key
+    let output = conformance("wrong secret", &[])?;
+    let report = String::from_utf8(output.stdout)?;
+    assert_eq!(output.status.code(), Some(1), "wrong key did not fail");
+    assert!(
+        report.contains("FAIL ping: authenticate failed: expected status 0 ok, got 41"),
+        "report was {}",
+        report
+    );
+
+    drop(server);
+    fs::remove_file(&config)?;
     Ok(())
 }